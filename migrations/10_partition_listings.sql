@@ -0,0 +1,66 @@
+-- Partitions listings by the month it was listed in, so that old months can be downsampled and dropped without
+-- touching the rest of the history. Partitions for upcoming months are created by the `maintenance` command (and
+-- the EDDN listener).
+ALTER TABLE listings RENAME TO listings_old;
+ALTER TABLE listings_old RENAME CONSTRAINT listings_pkey TO listings_old_pkey;
+ALTER INDEX idx_listings_market_name_listedat RENAME TO idx_listings_old_market_name_listedat;
+
+CREATE TABLE listings (
+    market_id BIGINT NOT NULL,
+    name VARCHAR(128) NOT NULL,
+    mean_price INT NOT NULL,
+    buy_price INT NOT NULL,
+    sell_price INT NOT NULL,
+    demand INT NOT NULL,
+    demand_bracket INT NOT NULL,
+    stock INT NOT NULL,
+    stock_bracket INT NOT NULL,
+    listed_at timestamp NOT NULL,
+
+    PRIMARY KEY (market_id, name, listed_at),
+    FOREIGN KEY (market_id) REFERENCES stations(market_id)
+) PARTITION BY RANGE (listed_at);
+
+CREATE INDEX idx_listings_market_name_listedat
+    ON listings (market_id, name, listed_at DESC);
+
+-- Each month's partition, and whether it has been downsampled to one snapshot per market per day
+CREATE TABLE listings_partitions (
+    month DATE PRIMARY KEY NOT NULL,
+    downsampled BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+-- Creates the partition of listings for the month containing the given day, e.g. listings_2025_01. Returns
+-- whether it was created, i.e. false if it already exists.
+CREATE FUNCTION create_listings_partition(day DATE) RETURNS BOOLEAN AS $$
+DECLARE
+    start DATE := date_trunc('month', day)::date;
+    partition TEXT := 'listings_' || to_char(start, 'YYYY_MM');
+BEGIN
+    IF to_regclass(partition) IS NOT NULL THEN
+        RETURN FALSE;
+    END IF;
+
+    EXECUTE format(
+        'CREATE TABLE %I PARTITION OF listings FOR VALUES FROM (%L) TO (%L)',
+        partition, start, (start + INTERVAL '1 month')::date
+    );
+    INSERT INTO listings_partitions (month) VALUES (start) ON CONFLICT DO NOTHING;
+    RETURN TRUE;
+END;
+$$ LANGUAGE plpgsql;
+
+SELECT create_listings_partition(month::date)
+FROM generate_series(
+    date_trunc('month', COALESCE((SELECT MIN(listed_at) FROM listings_old), NOW())),
+    date_trunc('month', NOW()) + INTERVAL '3 months',
+    INTERVAL '1 month'
+) AS month;
+
+INSERT INTO listings (
+    market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock, stock_bracket, listed_at
+)
+SELECT market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock, stock_bracket, listed_at
+FROM listings_old;
+
+DROP TABLE listings_old;
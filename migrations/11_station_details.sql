@@ -0,0 +1,17 @@
+-- Station details from Spansh's galaxy stations dump, so routes can be filtered by services and by
+-- planetary vs orbital stations
+ALTER TABLE stations ADD COLUMN station_type VARCHAR(64);
+ALTER TABLE stations ADD COLUMN planetary BOOLEAN;
+ALTER TABLE stations ADD COLUMN services TEXT[];
+ALTER TABLE stations ADD COLUMN primary_economy VARCHAR(64);
+-- map of economy name to its share of the station's economy, e.g. {"Refinery": 80.0, "Extraction": 20.0}
+ALTER TABLE stations ADD COLUMN economies JSONB;
+ALTER TABLE stations ADD COLUMN government VARCHAR(64);
+ALTER TABLE stations ADD COLUMN allegiance VARCHAR(64);
+ALTER TABLE stations ADD COLUMN controlling_faction VARCHAR(128);
+-- when Spansh last saw the station, and its market
+ALTER TABLE stations ADD COLUMN details_updated_at TIMESTAMP;
+ALTER TABLE stations ADD COLUMN market_updated_at TIMESTAMP;
+
+CREATE INDEX idx_stations_services ON stations USING GIN (services);
+CREATE INDEX idx_stations_planetary ON stations (planetary);
@@ -0,0 +1,5 @@
+-- The game's 64-bit system address, which Spansh uses to identify systems. Filled in by `ingest-edsm`.
+ALTER TABLE systems ADD COLUMN id64 BIGINT;
+
+CREATE INDEX idx_systems_id64 ON systems (id64);
+CREATE INDEX idx_stations_system_id ON stations (system_id);
@@ -0,0 +1,29 @@
+-- Landing pad sizes, in order so that "at least a medium pad" is `max_pad_size >= 'M'`
+CREATE TYPE pad_size AS ENUM ('S', 'M', 'L');
+
+-- Number of landing pads of each size
+ALTER TABLE stations ADD COLUMN pads_small SMALLINT;
+ALTER TABLE stations ADD COLUMN pads_medium SMALLINT;
+ALTER TABLE stations ADD COLUMN pads_large SMALLINT;
+
+-- The old "s,m,l" strings only recorded which sizes a station has, not how many, so they become counts of 1 or 0
+-- until the next Spansh import
+UPDATE stations SET
+    pads_small = (landing_pad LIKE '%s%')::int,
+    pads_medium = (landing_pad LIKE '%m%')::int,
+    pads_large = (landing_pad LIKE '%l%')::int
+WHERE landing_pad IS NOT NULL;
+
+ALTER TABLE stations DROP COLUMN landing_pad;
+-- left over from migration 4
+DROP TYPE LandingPad;
+
+ALTER TABLE stations ADD COLUMN max_pad_size pad_size GENERATED ALWAYS AS (
+    CASE
+        WHEN pads_large > 0 THEN 'L'::pad_size
+        WHEN pads_medium > 0 THEN 'M'::pad_size
+        WHEN pads_small > 0 THEN 'S'::pad_size
+    END
+) STORED;
+
+CREATE INDEX idx_stations_max_pad_size ON stations (max_pad_size);
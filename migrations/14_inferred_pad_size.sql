@@ -0,0 +1,20 @@
+-- Largest landing pad guessed from the station's type (see `edsm::inferred_pad_size`), for stations we don't
+-- have pad counts for
+ALTER TABLE stations ADD COLUMN inferred_pad_size pad_size;
+
+ALTER TABLE stations DROP COLUMN max_pad_size;
+ALTER TABLE stations ADD COLUMN max_pad_size pad_size GENERATED ALWAYS AS (
+    CASE
+        WHEN pads_large > 0 THEN 'L'::pad_size
+        WHEN pads_medium > 0 THEN 'M'::pad_size
+        WHEN pads_small > 0 THEN 'S'::pad_size
+        ELSE inferred_pad_size
+    END
+) STORED;
+-- Whether max_pad_size was guessed from the station type rather than counted
+ALTER TABLE stations ADD COLUMN pad_size_inferred BOOLEAN GENERATED ALWAYS AS (
+    COALESCE(pads_large, 0) + COALESCE(pads_medium, 0) + COALESCE(pads_small, 0) = 0
+    AND inferred_pad_size IS NOT NULL
+) STORED;
+
+CREATE INDEX idx_stations_max_pad_size ON stations (max_pad_size);
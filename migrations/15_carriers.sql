@@ -0,0 +1,28 @@
+-- Fleet carriers, tracked from EDDN. Carriers move, so their location is kept here rather than in stations.
+-- Each carrier also gets a row in stations (with its market ID as the station ID and no system) so that its
+-- market can be stored in listings like any other station's.
+CREATE TABLE carriers (
+    market_id BIGINT PRIMARY KEY NOT NULL,
+    -- e.g. "K7Q-BQL", which is also the carrier's station name in the journal
+    callsign VARCHAR(16) UNIQUE,
+    name VARCHAR(128),
+    system_id64 BIGINT,
+    system_name VARCHAR(128),
+    coords geometry(POINTZ),
+    located_at TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_carriers_coords ON carriers USING GIST (coords gist_geometry_ops_nd);
+
+-- Every system a carrier has been seen in, from when it was first seen there
+CREATE TABLE carrier_positions (
+    market_id BIGINT NOT NULL,
+    system_id64 BIGINT NOT NULL,
+    system_name VARCHAR(128) NOT NULL,
+    coords geometry(POINTZ),
+    seen_at TIMESTAMP NOT NULL,
+
+    PRIMARY KEY (market_id, seen_at),
+    FOREIGN KEY (market_id) REFERENCES carriers(market_id)
+);
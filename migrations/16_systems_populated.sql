@@ -0,0 +1,4 @@
+-- Whether a system is in EDSM's populated systems dump. Systems from the full systemsWithCoordinates dump are only
+-- needed for plotting routes, and are imported as unpopulated unless the populated dump also has them.
+ALTER TABLE systems ADD COLUMN populated BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE systems ALTER COLUMN populated DROP DEFAULT;
@@ -0,0 +1,2 @@
+-- `plot` looks systems up by name, which would otherwise scan every system in the full dump
+CREATE INDEX idx_systems_name ON systems (lower(name));
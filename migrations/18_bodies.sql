@@ -0,0 +1,25 @@
+-- Bodies from EDSM's bodies dump, so that planetary stations can be weighed by their body's gravity and distance
+-- from the arrival star. system_id isn't a foreign key because the dump has bodies in systems we may not have
+-- imported.
+CREATE TABLE bodies (
+    id BIGINT PRIMARY KEY NOT NULL,
+    id64 BIGINT,
+    name VARCHAR(128) NOT NULL,
+    system_id BIGINT,
+    system_id64 BIGINT,
+    -- Star or Planet, and e.g. High metal content world
+    body_type VARCHAR(16),
+    sub_type VARCHAR(64),
+    -- surface gravity in g
+    gravity DOUBLE PRECISION,
+    distance_to_arrival DOUBLE PRECISION,
+    landable BOOLEAN,
+    updated_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_bodies_system_id ON bodies (system_id);
+
+-- EDSM's body id of the body a station is on or orbiting. Not a foreign key, so that stations can be imported
+-- before bodies.
+ALTER TABLE stations ADD COLUMN body_id BIGINT;
+CREATE INDEX idx_stations_body_id ON stations (body_id);
@@ -0,0 +1,58 @@
+-- Minor faction and powerplay state of populated systems, from EDSM's populated systems dump and kept up to date
+-- from EDDN's FSDJump and Location events. Systems are keyed by id64 (the journal's SystemAddress). Each table
+-- holds the latest state, and the history tables get a row whenever it changes, to correlate with prices.
+
+-- Controlling faction and powerplay of each system
+CREATE TABLE system_states (
+    system_id64 BIGINT PRIMARY KEY NOT NULL,
+    controlling_faction VARCHAR(128),
+    controlling_power VARCHAR(64),
+    powers TEXT[],
+    powerplay_state VARCHAR(64),
+    updated_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE system_state_history (
+    system_id64 BIGINT NOT NULL,
+    controlling_faction VARCHAR(128),
+    controlling_power VARCHAR(64),
+    powers TEXT[],
+    powerplay_state VARCHAR(64),
+    seen_at TIMESTAMP NOT NULL,
+
+    PRIMARY KEY (system_id64, seen_at)
+);
+
+-- Each minor faction present in a system. Factions that retreat are removed.
+CREATE TABLE faction_presences (
+    system_id64 BIGINT NOT NULL,
+    faction VARCHAR(128) NOT NULL,
+    -- share of the system's influence, from 0 to 1
+    influence REAL,
+    -- e.g. Boom, Expansion or Famine
+    state VARCHAR(64),
+    active_states TEXT[] NOT NULL,
+    pending_states TEXT[] NOT NULL,
+    recovering_states TEXT[] NOT NULL,
+    allegiance VARCHAR(64),
+    government VARCHAR(64),
+    happiness VARCHAR(64),
+    updated_at TIMESTAMP NOT NULL,
+
+    PRIMARY KEY (system_id64, faction)
+);
+
+CREATE INDEX idx_faction_presences_faction ON faction_presences (faction);
+
+CREATE TABLE faction_presence_history (
+    system_id64 BIGINT NOT NULL,
+    faction VARCHAR(128) NOT NULL,
+    influence REAL,
+    state VARCHAR(64),
+    active_states TEXT[] NOT NULL,
+    pending_states TEXT[] NOT NULL,
+    recovering_states TEXT[] NOT NULL,
+    seen_at TIMESTAMP NOT NULL,
+
+    PRIMARY KEY (system_id64, faction, seen_at)
+);
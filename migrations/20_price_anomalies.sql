@@ -0,0 +1,36 @@
+-- Rolling galaxy-wide price distribution of each commodity, recomputed periodically by the listener from the
+-- latest listings. Prices are compared against it to catch obviously wrong ones from EDDN.
+CREATE TABLE commodity_price_stats (
+    name VARCHAR(128) PRIMARY KEY NOT NULL,
+    -- median and median absolute deviation of buy prices at stations with stock
+    buy_median DOUBLE PRECISION,
+    buy_mad DOUBLE PRECISION,
+    buy_samples INT NOT NULL,
+    -- same for sell prices at stations with demand
+    sell_median DOUBLE PRECISION,
+    sell_mad DOUBLE PRECISION,
+    sell_samples INT NOT NULL,
+    computed_at TIMESTAMP NOT NULL
+);
+
+-- Listings whose prices looked wrong. Quarantined listings were held back from listings until reviewed; flagged
+-- ones were inserted anyway. Reviewing sets the status to accepted or rejected.
+CREATE TABLE price_anomalies (
+    id BIGSERIAL PRIMARY KEY,
+    market_id BIGINT NOT NULL,
+    name VARCHAR(128) NOT NULL,
+    mean_price INT NOT NULL,
+    buy_price INT NOT NULL,
+    sell_price INT NOT NULL,
+    demand INT NOT NULL,
+    demand_bracket INT NOT NULL,
+    stock INT NOT NULL,
+    stock_bracket INT NOT NULL,
+    listed_at TIMESTAMP NOT NULL,
+    reason TEXT NOT NULL,
+    status VARCHAR(16) NOT NULL CHECK (status IN ('quarantined', 'flagged', 'accepted', 'rejected')),
+    detected_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    reviewed_at TIMESTAMP
+);
+
+CREATE INDEX idx_price_anomalies_status ON price_anomalies (status);
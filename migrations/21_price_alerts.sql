@@ -0,0 +1,41 @@
+-- Price alert rules, evaluated by the listener against every market update. A rule fires when a commodity's buy
+-- or sell price goes above or below its threshold at a market matching the rule's filters, and is delivered to
+-- the rule's webhook.
+CREATE TABLE alert_rules (
+    id SERIAL PRIMARY KEY,
+    commodity VARCHAR(128) NOT NULL,
+    -- which price to watch: buy (what the station sells for) or sell (what it pays)
+    side VARCHAR(4) NOT NULL CHECK (side IN ('buy', 'sell')),
+    direction VARCHAR(5) NOT NULL CHECK (direction IN ('above', 'below')),
+    threshold INT NOT NULL,
+    -- only markets within radius ly of this system, if set
+    system_id BIGINT,
+    radius DOUBLE PRECISION,
+    min_demand INT NOT NULL DEFAULT 0,
+    min_stock INT NOT NULL DEFAULT 0,
+    min_pad_size pad_size,
+    webhook_url TEXT NOT NULL,
+    -- the rule doesn't fire again for the same market until this long after it last fired
+    cooldown_minutes INT NOT NULL DEFAULT 60,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    CHECK ((system_id IS NULL) = (radius IS NULL)),
+    FOREIGN KEY (system_id) REFERENCES systems(id)
+);
+
+CREATE INDEX idx_alert_rules_commodity ON alert_rules (commodity);
+
+-- Every time a rule fired. Failed deliveries (with an error) don't count towards the cooldown.
+CREATE TABLE alert_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    rule_id INT NOT NULL,
+    market_id BIGINT NOT NULL,
+    commodity VARCHAR(128) NOT NULL,
+    price INT NOT NULL,
+    fired_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    delivered_at TIMESTAMP,
+    error TEXT,
+    FOREIGN KEY (rule_id) REFERENCES alert_rules(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_alert_deliveries_rule_id_market_id ON alert_deliveries (rule_id, market_id, fired_at DESC);
@@ -0,0 +1,29 @@
+-- The latest snapshot of each market, so that "current prices" queries don't have to scan the whole listings
+-- history. Kept up to date by the EDDN listener; see `rebuild-latest-listings` to recompute it from scratch.
+CREATE TABLE latest_listings (
+    market_id BIGINT NOT NULL,
+    name VARCHAR(128) NOT NULL,
+    mean_price INT NOT NULL,
+    buy_price INT NOT NULL,
+    sell_price INT NOT NULL,
+    demand INT NOT NULL,
+    demand_bracket INT NOT NULL,
+    stock INT NOT NULL,
+    stock_bracket INT NOT NULL,
+    listed_at timestamp NOT NULL,
+
+    PRIMARY KEY (market_id, name),
+    FOREIGN KEY (market_id) REFERENCES stations(market_id)
+);
+
+CREATE INDEX idx_latest_listings_name ON latest_listings (name);
+
+INSERT INTO latest_listings
+SELECT l.market_id, l.name, l.mean_price, l.buy_price, l.sell_price, l.demand, l.demand_bracket, l.stock,
+    l.stock_bracket, l.listed_at
+FROM listings l
+INNER JOIN (
+    SELECT market_id, MAX(listed_at) AS listed_at
+    FROM listings
+    GROUP BY market_id
+) latest ON l.market_id = latest.market_id AND l.listed_at = latest.listed_at;
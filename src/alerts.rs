@@ -0,0 +1,540 @@
+// Price alerts: rules stored in Postgres, e.g. "Painite sells for more than 300,000 within 50 ly of Sol", that the
+// listener evaluates against every market update it inserts. Matching rules are delivered as a JSON POST to the
+// rule's webhook, and don't fire again for the same market until their cooldown has passed.
+
+use chrono::NaiveDateTime;
+use comfy_table::{presets::UTF8_FULL, Table};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+
+use crate::{query::PadSize, stream::MarketUpdate, Error, Result};
+
+/// Which of a listing's prices a rule watches
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PriceSide {
+    /// The price the station sells the commodity for
+    Buy,
+    /// The price the station pays for the commodity
+    Sell,
+}
+
+impl PriceSide {
+    fn as_str(self) -> &'static str {
+        match self {
+            PriceSide::Buy => "buy",
+            PriceSide::Sell => "sell",
+        }
+    }
+}
+
+/// Whether a rule fires when the price goes above or below its threshold
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertDirection {
+    Above,
+    Below,
+}
+
+impl AlertDirection {
+    fn as_str(self) -> &'static str {
+        match self {
+            AlertDirection::Above => "above",
+            AlertDirection::Below => "below",
+        }
+    }
+}
+
+/// A price alert rule to add
+#[derive(Debug, Clone)]
+pub struct NewAlertRule {
+    pub commodity: String,
+    pub side: PriceSide,
+    pub direction: AlertDirection,
+    pub threshold: i32,
+    /// Only markets within `radius` ly of this system (by name), if set
+    pub reference_system: Option<String>,
+    pub radius: Option<f64>,
+    pub min_demand: i32,
+    pub min_stock: i32,
+    /// Only stations with a landing pad of at least this size
+    pub min_pad_size: Option<PadSize>,
+    pub webhook_url: String,
+    pub cooldown_minutes: i32,
+}
+
+/// A price alert rule
+#[derive(Debug, Clone)]
+pub struct AlertRule {
+    pub id: i32,
+    pub commodity: String,
+    pub side: String,
+    pub direction: String,
+    pub threshold: i32,
+    pub reference_system: Option<String>,
+    pub radius: Option<f64>,
+    pub min_demand: i32,
+    pub min_stock: i32,
+    pub min_pad_size: Option<PadSize>,
+    pub webhook_url: String,
+    pub cooldown_minutes: i32,
+    pub enabled: bool,
+    pub last_fired_at: Option<NaiveDateTime>,
+}
+
+/// What's POSTed to a rule's webhook when it fires
+#[derive(Serialize, Debug, Clone)]
+pub struct AlertPayload {
+    pub rule_id: i32,
+    pub commodity: String,
+    pub side: String,
+    pub direction: String,
+    pub threshold: i32,
+    pub price: i32,
+    pub demand: i32,
+    pub stock: i32,
+    pub market_id: i64,
+    pub station: String,
+    pub system: String,
+    /// Distance from the rule's reference system in ly, if it has one
+    pub distance: Option<f64>,
+    pub listed_at: NaiveDateTime,
+    /// Human readable summary, e.g. for chat webhooks
+    pub message: String,
+}
+
+/// A rule that fired, waiting to be delivered
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub delivery_id: i64,
+    pub webhook_url: String,
+    pub payload: AlertPayload,
+}
+
+/// Adds a price alert rule. Returns its ID, or None if the reference system isn't known.
+pub async fn add_rule(rule: &NewAlertRule, pool: &Pool<Postgres>) -> Result<Option<i32>, Error> {
+    let system_id = match &rule.reference_system {
+        Some(name) => {
+            let id = sqlx::query_scalar!(
+                "SELECT id FROM systems WHERE lower(name) = lower($1) LIMIT 1;",
+                name
+            )
+            .fetch_optional(pool)
+            .await?;
+            match id {
+                Some(id) => Some(id),
+                None => return Ok(None),
+            }
+        }
+        None => None,
+    };
+
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO alert_rules (
+            commodity, side, direction, threshold, system_id, radius, min_demand, min_stock, min_pad_size,
+            webhook_url, cooldown_minutes
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11
+        )
+        RETURNING id;
+    "#,
+        rule.commodity,
+        rule.side.as_str(),
+        rule.direction.as_str(),
+        rule.threshold,
+        system_id,
+        rule.radius.filter(|_| system_id.is_some()),
+        rule.min_demand,
+        rule.min_stock,
+        rule.min_pad_size as Option<PadSize>,
+        rule.webhook_url,
+        rule.cooldown_minutes
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(Some(id))
+}
+
+/// Returns every price alert rule, with when it last fired
+pub async fn list_rules(pool: &Pool<Postgres>) -> Result<Vec<AlertRule>, Error> {
+    let rules = sqlx::query_as!(
+        AlertRule,
+        r#"
+        SELECT r.id, r.commodity, r.side, r.direction, r.threshold, s.name AS "reference_system?", r.radius,
+            r.min_demand, r.min_stock, r.min_pad_size AS "min_pad_size: PadSize", r.webhook_url,
+            r.cooldown_minutes, r.enabled,
+            (SELECT MAX(d.fired_at) FROM alert_deliveries d WHERE d.rule_id = r.id) AS last_fired_at
+        FROM alert_rules r
+        LEFT JOIN systems s ON s.id = r.system_id
+        ORDER BY r.id;
+    "#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rules)
+}
+
+/// Removes a price alert rule and its deliveries. Returns whether it existed.
+pub async fn remove_rule(id: i32, pool: &Pool<Postgres>) -> Result<bool, Error> {
+    let removed = sqlx::query!("DELETE FROM alert_rules WHERE id = $1;", id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(removed > 0)
+}
+
+/// Finds the rules matching a market update that aren't cooling down for its market, and records that they fired.
+/// Listings flagged as price anomalies are skipped. Returns the alerts to deliver.
+///
+/// Updates of the same market are evaluated one at a time, so that a rule can't fire twice within its cooldown
+/// when two arrive together.
+pub async fn evaluate_alerts(
+    update: &MarketUpdate,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<Alert>, Error> {
+    let mut names = Vec::with_capacity(update.commodities.len());
+    let mut buy_prices = Vec::with_capacity(update.commodities.len());
+    let mut sell_prices = Vec::with_capacity(update.commodities.len());
+    let mut demands = Vec::with_capacity(update.commodities.len());
+    let mut stocks = Vec::with_capacity(update.commodities.len());
+    for commodity in &update.commodities {
+        names.push(commodity.name.clone());
+        buy_prices.push(commodity.buy_price);
+        sell_prices.push(commodity.sell_price);
+        demands.push(commodity.demand);
+        stocks.push(commodity.stock);
+    }
+
+    let mut transaction = pool.begin().await?;
+    // held until the deliveries are committed, so the next evaluation of this market sees them
+    sqlx::query!("SELECT pg_advisory_xact_lock($1);", update.market_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    let fired = sqlx::query!(
+        r#"
+        WITH market AS (
+            SELECT stat.max_pad_size, COALESCE(c.coords, sys.coords) AS coords
+            FROM stations stat
+            LEFT JOIN systems sys ON sys.id = stat.system_id
+            LEFT JOIN carriers c ON c.market_id = stat.market_id
+            WHERE stat.market_id = $1
+        ),
+        commodities AS (
+            SELECT * FROM unnest($2::text[], $3::int4[], $4::int4[], $5::int4[], $6::int4[])
+                AS c(name, buy_price, sell_price, demand, stock)
+        ),
+        candidates AS (
+            SELECT r.id, r.commodity, r.side, r.direction, r.threshold, r.webhook_url, c.demand, c.stock,
+                CASE WHEN r.side = 'buy' THEN c.buy_price ELSE c.sell_price END AS price,
+                ST_3DDistance(ref.coords, m.coords) AS distance
+            FROM alert_rules r
+            JOIN commodities c ON c.name = r.commodity
+            CROSS JOIN market m
+            LEFT JOIN systems ref ON ref.id = r.system_id
+            WHERE r.enabled
+            AND c.demand >= r.min_demand
+            AND c.stock >= r.min_stock
+            AND (r.min_pad_size IS NULL OR m.max_pad_size >= r.min_pad_size)
+            AND (r.system_id IS NULL OR ST_3DDWithin(ref.coords, m.coords, r.radius))
+            -- flagged prices are probably bogus, so wait for them to be reviewed
+            AND NOT EXISTS (
+                SELECT 1 FROM price_anomalies a
+                WHERE a.market_id = $1 AND a.name = c.name AND a.listed_at = $7 AND a.status = 'flagged'
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM alert_deliveries d
+                WHERE d.rule_id = r.id AND d.market_id = $1 AND d.error IS NULL
+                AND d.fired_at > NOW() - make_interval(mins => r.cooldown_minutes)
+            )
+        ),
+        matches AS (
+            SELECT * FROM candidates
+            WHERE price > 0
+            AND CASE WHEN direction = 'above' THEN price > threshold ELSE price < threshold END
+        ),
+        deliveries AS (
+            INSERT INTO alert_deliveries (rule_id, market_id, commodity, price)
+            SELECT id, $1, commodity, price FROM matches
+            RETURNING id, rule_id
+        )
+        SELECT d.id AS "delivery_id!", m.id AS "id!", m.commodity AS "commodity!", m.side AS "side!",
+            m.direction AS "direction!", m.threshold AS "threshold!", m.webhook_url AS "webhook_url!",
+            m.demand AS "demand!", m.stock AS "stock!", m.price AS "price!", m.distance
+        FROM deliveries d
+        JOIN matches m ON m.id = d.rule_id;
+    "#,
+        update.market_id,
+        &names,
+        &buy_prices,
+        &sell_prices,
+        &demands,
+        &stocks,
+        update.listed_at
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    let mut alerts = Vec::with_capacity(fired.len());
+    for row in fired {
+        let message = format!(
+            "{} {} price is {} at {} in {} ({} {})",
+            row.commodity,
+            row.side,
+            row.price,
+            update.station_name,
+            update.system_name,
+            row.direction,
+            row.threshold
+        );
+        alerts.push(Alert {
+            delivery_id: row.delivery_id,
+            webhook_url: row.webhook_url,
+            payload: AlertPayload {
+                rule_id: row.id,
+                commodity: row.commodity,
+                side: row.side,
+                direction: row.direction,
+                threshold: row.threshold,
+                price: row.price,
+                demand: row.demand,
+                stock: row.stock,
+                market_id: update.market_id,
+                station: update.station_name.clone(),
+                system: update.system_name.clone(),
+                distance: row.distance,
+                listed_at: update.listed_at,
+                message,
+            },
+        });
+    }
+    Ok(alerts)
+}
+
+/// POSTs an alert to its webhook. Returns why it couldn't be delivered, if it wasn't, including non-2xx
+/// responses.
+async fn send_alert(alert: &Alert, client: &reqwest::Client) -> Option<String> {
+    client
+        .post(&alert.webhook_url)
+        .json(&alert.payload)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .err()
+        .map(|error| error.to_string())
+}
+
+/// POSTs an alert to its webhook, and records whether it was delivered. A failed delivery is only logged, and
+/// doesn't count towards the rule's cooldown so that it can fire again on the market's next update.
+pub async fn deliver_alert(
+    alert: &Alert,
+    client: &reqwest::Client,
+    pool: &Pool<Postgres>,
+) -> Result<(), Error> {
+    let error = send_alert(alert, client).await;
+
+    match &error {
+        Some(error) => warn!(
+            "Failed to deliver price alert {} to {}: {}",
+            alert.payload.rule_id, alert.webhook_url, error
+        ),
+        None => info!("Price alert: {}", alert.payload.message),
+    }
+
+    sqlx::query!(
+        "UPDATE alert_deliveries SET delivered_at = NOW(), error = $2 WHERE id = $1;",
+        alert.delivery_id,
+        error
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Adds a price alert rule from the command line
+pub async fn add(rule: NewAlertRule, url: String, pool_size: u32) -> Result<()> {
+    if rule.cooldown_minutes < 0 {
+        return Err(Error::InvalidArgument(
+            "Cooldown can't be negative".to_string(),
+        ));
+    }
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    let id = add_rule(&rule, &pool).await?.ok_or_else(|| {
+        Error::NotFound(format!(
+            "Unknown system {}",
+            rule.reference_system.as_deref().unwrap_or_default()
+        ))
+    })?;
+    info!("Added price alert rule {}", id);
+    Ok(())
+}
+
+/// Prints every price alert rule
+pub async fn list(url: String, pool_size: u32) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    let rules = list_rules(&pool).await?;
+    if rules.is_empty() {
+        println!("No price alert rules");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL);
+    table.set_header(vec![
+        "ID",
+        "Commodity",
+        "Price",
+        "Near",
+        "Min demand",
+        "Min stock",
+        "Min pad",
+        "Webhook",
+        "Cooldown (min)",
+        "Last fired",
+    ]);
+    for rule in rules {
+        let near = match (rule.reference_system, rule.radius) {
+            (Some(system), Some(radius)) => format!("{radius} ly of {system}"),
+            _ => String::new(),
+        };
+        let pad = match rule.min_pad_size {
+            Some(PadSize::Small) => "S",
+            Some(PadSize::Medium) => "M",
+            Some(PadSize::Large) => "L",
+            None => "",
+        };
+        table.add_row(vec![
+            rule.id.to_string(),
+            rule.commodity,
+            format!("{} {} {}", rule.side, rule.direction, rule.threshold),
+            near,
+            rule.min_demand.to_string(),
+            rule.min_stock.to_string(),
+            pad.to_string(),
+            rule.webhook_url,
+            rule.cooldown_minutes.to_string(),
+            rule.last_fired_at
+                .map(|time| time.to_string())
+                .unwrap_or_default(),
+        ]);
+    }
+    println!("{}", table);
+    Ok(())
+}
+
+/// Removes a price alert rule from the command line
+pub async fn remove(id: i32, url: String, pool_size: u32) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    if !remove_rule(id, &pool).await? {
+        return Err(Error::NotFound(format!("No price alert rule {}", id)));
+    }
+    info!("Removed price alert rule {}", id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<serde_json::Value>>>;
+
+    /// Starts a webhook that records what's POSTed to it, and fails on /fail. Returns its base URL.
+    async fn stub_webhook(received: Received) -> String {
+        async fn receive(
+            State(received): State<Received>,
+            Json(body): Json<serde_json::Value>,
+        ) -> StatusCode {
+            received.lock().unwrap().push(body);
+            StatusCode::NO_CONTENT
+        }
+
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .route(
+                "/fail",
+                post(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+            )
+            .with_state(received);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    fn alert(webhook_url: String) -> Alert {
+        Alert {
+            delivery_id: 1,
+            webhook_url,
+            payload: AlertPayload {
+                rule_id: 7,
+                commodity: "painite".to_string(),
+                side: "sell".to_string(),
+                direction: "above".to_string(),
+                threshold: 300_000,
+                price: 350_000,
+                demand: 1200,
+                stock: 0,
+                market_id: 128_000_000,
+                station: "Abraham Lincoln".to_string(),
+                system: "Sol".to_string(),
+                distance: Some(4.2),
+                listed_at: NaiveDate::from_ymd_opt(2025, 1, 1)
+                    .unwrap()
+                    .and_hms_opt(12, 0, 0)
+                    .unwrap(),
+                message: "painite sell price is 350000 at Abraham Lincoln in Sol (above 300000)"
+                    .to_string(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_the_payload() {
+        let received = Received::default();
+        let base = stub_webhook(received.clone()).await;
+
+        let error = send_alert(&alert(format!("{}/hook", base)), &reqwest::Client::new()).await;
+        assert_eq!(error, None);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["rule_id"], 7);
+        assert_eq!(received[0]["commodity"], "painite");
+        assert_eq!(received[0]["price"], 350_000);
+        assert_eq!(received[0]["system"], "Sol");
+        assert_eq!(received[0]["listed_at"], "2025-01-01T12:00:00");
+    }
+
+    #[tokio::test]
+    async fn reports_failed_deliveries() {
+        let base = stub_webhook(Received::default()).await;
+
+        let error = send_alert(&alert(format!("{}/fail", base)), &reqwest::Client::new()).await;
+        assert!(error.unwrap().contains("500"));
+
+        // nothing listening
+        let error = send_alert(
+            &alert("http://127.0.0.1:1/hook".to_string()),
+            &reqwest::Client::new(),
+        )
+        .await;
+        assert!(error.is_some());
+    }
+}
@@ -0,0 +1,644 @@
+// Catches obviously wrong prices from EDDN before they end up in the listings. Every so often the listener
+// computes the median and median absolute deviation (MAD) of each commodity's buy and sell prices across the
+// latest market snapshots, then checks each incoming listing against them. Listings far from the median are
+// recorded in `price_anomalies`, and either quarantined (held back until someone accepts them, the default) or
+// just flagged (inserted anyway, so that they can be rejected later) depending on the listener's configuration.
+
+use std::{collections::HashMap, str::FromStr};
+
+use chrono::NaiveDateTime;
+use comfy_table::{presets::UTF8_FULL, Table};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+
+use crate::{maintenance, Error, Result};
+
+/// Only market snapshots this recent count towards the price distributions
+pub const STATS_WINDOW_DAYS: i32 = 30;
+/// How often the listener recomputes the price distributions
+pub const STATS_REFRESH_HOURS: u64 = 6;
+/// Commodities seen at fewer markets than this aren't checked, as their distribution isn't meaningful
+pub const MIN_SAMPLES: i32 = 20;
+/// Scales the MAD to a standard deviation, for normally distributed prices
+const MAD_SCALE: f64 = 1.4826;
+/// Lower bound on the spread, as a fraction of the median, so that commodities sold at the same price everywhere
+/// don't flag every small change
+const MIN_SPREAD: f64 = 0.05;
+
+/// What the listener does with listings whose prices look wrong
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AnomalyAction {
+    /// Don't check prices
+    Off,
+    /// Insert the listing, but record it for review. Flagged prices show up in stats and offers (though they
+    /// don't fire alerts) until they're rejected.
+    Flag,
+    /// Hold the listing back until it's accepted
+    #[default]
+    Quarantine,
+}
+
+impl FromStr for AnomalyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(s, true)
+    }
+}
+
+/// How incoming prices are checked
+#[derive(Debug, Clone, Copy)]
+pub struct PriceValidation {
+    pub action: AnomalyAction,
+    /// Listings more than this many (robust) standard deviations from the median are anomalies
+    pub threshold: f64,
+}
+
+/// Distribution of a commodity's prices across markets
+#[derive(Debug, Clone)]
+pub struct PriceStats {
+    pub name: String,
+    pub buy_median: Option<f64>,
+    pub buy_mad: Option<f64>,
+    pub buy_samples: i32,
+    pub sell_median: Option<f64>,
+    pub sell_mad: Option<f64>,
+    pub sell_samples: i32,
+}
+
+/// Checks one side (buy or sell) of a listing. Returns why the price is an anomaly, if it is.
+fn check_price(
+    side: &str,
+    price: i32,
+    median: Option<f64>,
+    mad: Option<f64>,
+    samples: i32,
+    threshold: f64,
+) -> Option<String> {
+    if price <= 0 {
+        return Some(format!("{side} price of {price}"));
+    }
+    let (Some(median), Some(mad)) = (median, mad) else {
+        return None;
+    };
+    if samples < MIN_SAMPLES {
+        return None;
+    }
+    let spread = (MAD_SCALE * mad).max(MIN_SPREAD * median).max(1.0);
+    let deviations = (price as f64 - median).abs() / spread;
+    (deviations > threshold).then(|| {
+        format!(
+            "{side} price of {price} is {deviations:.1} deviations from the median of {median:.0}"
+        )
+    })
+}
+
+impl PriceValidation {
+    /// Returns why the listing's prices are anomalies, or None if they look fine. Buy prices are only checked
+    /// when there's stock, and sell prices when there's demand, as the game lists meaningless prices otherwise.
+    pub fn check(
+        &self,
+        commodity: &eddn::MarketCommodity,
+        stats: Option<&PriceStats>,
+    ) -> Option<String> {
+        self.check_prices(
+            commodity.buy_price,
+            commodity.sell_price,
+            commodity.stock,
+            commodity.demand,
+            stats,
+        )
+    }
+
+    fn check_prices(
+        &self,
+        buy_price: i32,
+        sell_price: i32,
+        stock: i32,
+        demand: i32,
+        stats: Option<&PriceStats>,
+    ) -> Option<String> {
+        let mut reasons = Vec::new();
+        if stock > 0 {
+            reasons.extend(check_price(
+                "buy",
+                buy_price,
+                stats.and_then(|stats| stats.buy_median),
+                stats.and_then(|stats| stats.buy_mad),
+                stats.map_or(0, |stats| stats.buy_samples),
+                self.threshold,
+            ));
+        }
+        if demand > 0 {
+            reasons.extend(check_price(
+                "sell",
+                sell_price,
+                stats.and_then(|stats| stats.sell_median),
+                stats.and_then(|stats| stats.sell_mad),
+                stats.map_or(0, |stats| stats.sell_samples),
+                self.threshold,
+            ));
+        }
+        (!reasons.is_empty()).then(|| reasons.join(", "))
+    }
+}
+
+/// Recomputes the price distribution of every commodity from the latest market snapshots. Returns the number of
+/// commodities.
+pub async fn refresh_price_stats(pool: &Pool<Postgres>) -> Result<u64, Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!("DELETE FROM commodity_price_stats;")
+        .execute(&mut *transaction)
+        .await?;
+    let computed = sqlx::query!(
+        r#"
+        WITH recent AS (
+            SELECT name, buy_price, sell_price, stock, demand
+            FROM latest_listings
+            WHERE listed_at >= NOW() - make_interval(days => $1)
+        ),
+        buy AS (
+            SELECT name, percentile_cont(0.5) WITHIN GROUP (ORDER BY buy_price) AS median, COUNT(*) AS samples
+            FROM recent
+            WHERE stock > 0 AND buy_price > 0
+            GROUP BY name
+        ),
+        buy_mad AS (
+            SELECT r.name, percentile_cont(0.5) WITHIN GROUP (ORDER BY abs(r.buy_price - b.median)) AS mad
+            FROM recent r
+            JOIN buy b ON b.name = r.name
+            WHERE r.stock > 0 AND r.buy_price > 0
+            GROUP BY r.name
+        ),
+        sell AS (
+            SELECT name, percentile_cont(0.5) WITHIN GROUP (ORDER BY sell_price) AS median, COUNT(*) AS samples
+            FROM recent
+            WHERE demand > 0 AND sell_price > 0
+            GROUP BY name
+        ),
+        sell_mad AS (
+            SELECT r.name, percentile_cont(0.5) WITHIN GROUP (ORDER BY abs(r.sell_price - s.median)) AS mad
+            FROM recent r
+            JOIN sell s ON s.name = r.name
+            WHERE r.demand > 0 AND r.sell_price > 0
+            GROUP BY r.name
+        ),
+        names AS (
+            SELECT name FROM buy UNION SELECT name FROM sell
+        )
+        INSERT INTO commodity_price_stats (
+            name, buy_median, buy_mad, buy_samples, sell_median, sell_mad, sell_samples, computed_at
+        )
+        SELECT n.name, b.median, bm.mad, COALESCE(b.samples, 0), s.median, sm.mad, COALESCE(s.samples, 0), NOW()
+        FROM names n
+        LEFT JOIN buy b ON b.name = n.name
+        LEFT JOIN buy_mad bm ON bm.name = n.name
+        LEFT JOIN sell s ON s.name = n.name
+        LEFT JOIN sell_mad sm ON sm.name = n.name;
+    "#,
+        STATS_WINDOW_DAYS
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    transaction.commit().await?;
+    Ok(computed)
+}
+
+/// Returns the price distributions of the given commodities, by name
+pub async fn price_stats(
+    names: &[String],
+    pool: &Pool<Postgres>,
+) -> Result<HashMap<String, PriceStats>, Error> {
+    let stats = sqlx::query_as!(
+        PriceStats,
+        r#"
+        SELECT name, buy_median, buy_mad, buy_samples, sell_median, sell_mad, sell_samples
+        FROM commodity_price_stats
+        WHERE name = ANY($1);
+    "#,
+        names
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(stats
+        .into_iter()
+        .map(|stats| (stats.name.clone(), stats))
+        .collect())
+}
+
+/// Records a listing whose prices look wrong, as either quarantined or flagged, in the transaction inserting the
+/// rest of the market update
+pub async fn record_anomaly(
+    market_id: i64,
+    commodity: &eddn::MarketCommodity,
+    listed_at: NaiveDateTime,
+    reason: &str,
+    action: AnomalyAction,
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+) -> Result<(), Error> {
+    let status = match action {
+        AnomalyAction::Quarantine => "quarantined",
+        _ => "flagged",
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO price_anomalies (
+            market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock, stock_bracket,
+            listed_at, reason, status
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12
+        );
+    "#,
+        market_id,
+        commodity.name,
+        commodity.mean_price,
+        commodity.buy_price,
+        commodity.sell_price,
+        commodity.demand,
+        commodity.demand_bracket,
+        commodity.stock,
+        commodity.stock_bracket,
+        listed_at,
+        reason,
+        status
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// A listing recorded for review
+#[derive(Debug, Clone)]
+pub struct PriceAnomaly {
+    pub id: i64,
+    pub market_id: i64,
+    pub station_name: Option<String>,
+    pub system_name: Option<String>,
+    pub name: String,
+    pub mean_price: i32,
+    pub buy_price: i32,
+    pub sell_price: i32,
+    pub demand: i32,
+    pub demand_bracket: i32,
+    pub stock: i32,
+    pub stock_bracket: i32,
+    pub listed_at: NaiveDateTime,
+    pub reason: String,
+    pub status: String,
+    pub detected_at: NaiveDateTime,
+}
+
+/// Returns the anomalies waiting for review (quarantined or flagged), or every anomaly if `all` is set, oldest
+/// first
+pub async fn list_anomalies(all: bool, pool: &Pool<Postgres>) -> Result<Vec<PriceAnomaly>, Error> {
+    let anomalies = sqlx::query_as!(
+        PriceAnomaly,
+        r#"
+        SELECT a.id, a.market_id, st.name AS "station_name?", sy.name AS "system_name?", a.name, a.mean_price,
+            a.buy_price, a.sell_price, a.demand, a.demand_bracket, a.stock, a.stock_bracket, a.listed_at,
+            a.reason, a.status, a.detected_at
+        FROM price_anomalies a
+        LEFT JOIN stations st ON st.market_id = a.market_id
+        LEFT JOIN systems sy ON sy.id = st.system_id
+        WHERE $1 OR a.status IN ('quarantined', 'flagged')
+        ORDER BY a.id;
+    "#,
+        all
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(anomalies)
+}
+
+/// Looks up an anomaly waiting for review, locking it until the transaction ends
+async fn pending_anomaly(
+    id: i64,
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+) -> Result<Option<PriceAnomaly>, Error> {
+    let anomaly = sqlx::query_as!(
+        PriceAnomaly,
+        r#"
+        SELECT a.id, a.market_id, NULL::text AS "station_name?", NULL::text AS "system_name?", a.name,
+            a.mean_price, a.buy_price, a.sell_price, a.demand, a.demand_bracket, a.stock, a.stock_bracket,
+            a.listed_at, a.reason, a.status, a.detected_at
+        FROM price_anomalies a
+        WHERE a.id = $1 AND a.status IN ('quarantined', 'flagged')
+        FOR UPDATE;
+    "#,
+        id
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    Ok(anomaly)
+}
+
+/// Marks an anomaly as reviewed
+async fn set_status(
+    id: i64,
+    status: &str,
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE price_anomalies SET status = $2, reviewed_at = NOW() WHERE id = $1;",
+        id,
+        status
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// Accepts an anomaly's prices as genuine. A quarantined listing is inserted into the listings (and the latest
+/// listings, unless the market has a newer snapshot); a flagged one is already there. Returns the anomaly, or
+/// None if there's no anomaly with this ID waiting for review.
+pub async fn accept_anomaly(id: i64, pool: &Pool<Postgres>) -> Result<Option<PriceAnomaly>, Error> {
+    let mut transaction = pool.begin().await?;
+    let Some(anomaly) = pending_anomaly(id, &mut transaction).await? else {
+        return Ok(None);
+    };
+
+    if anomaly.status == "quarantined" {
+        maintenance::create_partition(anomaly.listed_at.date(), &mut *transaction).await?;
+        sqlx::query!(
+            r#"
+            WITH inserted AS (
+                INSERT INTO listings (
+                    market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock,
+                    stock_bracket, listed_at
+                )
+                SELECT market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock,
+                    stock_bracket, listed_at
+                FROM price_anomalies
+                WHERE id = $1
+                ON CONFLICT DO NOTHING
+                RETURNING *
+            )
+            INSERT INTO latest_listings (
+                market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock, stock_bracket,
+                listed_at
+            )
+            SELECT market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock,
+                stock_bracket, listed_at
+            FROM inserted
+            WHERE NOT EXISTS (
+                SELECT 1 FROM latest_listings l
+                WHERE l.market_id = inserted.market_id AND l.listed_at > inserted.listed_at
+            )
+            ON CONFLICT (market_id, name) DO UPDATE SET
+                mean_price = EXCLUDED.mean_price,
+                buy_price = EXCLUDED.buy_price,
+                sell_price = EXCLUDED.sell_price,
+                demand = EXCLUDED.demand,
+                demand_bracket = EXCLUDED.demand_bracket,
+                stock = EXCLUDED.stock,
+                stock_bracket = EXCLUDED.stock_bracket,
+                listed_at = EXCLUDED.listed_at
+            WHERE latest_listings.listed_at <= EXCLUDED.listed_at;
+        "#,
+            id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    set_status(id, "accepted", &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(Some(anomaly))
+}
+
+/// Rejects an anomaly's prices as bogus. A flagged listing is deleted from the listings, and if it was the
+/// market's latest snapshot of the commodity, the previous listing takes its place; a quarantined one was never
+/// inserted. Returns the anomaly, or None if there's no anomaly with this ID waiting for review.
+pub async fn reject_anomaly(id: i64, pool: &Pool<Postgres>) -> Result<Option<PriceAnomaly>, Error> {
+    let mut transaction = pool.begin().await?;
+    let Some(anomaly) = pending_anomaly(id, &mut transaction).await? else {
+        return Ok(None);
+    };
+
+    if anomaly.status == "flagged" {
+        sqlx::query!(
+            "DELETE FROM listings WHERE market_id = $1 AND name = $2 AND listed_at = $3;",
+            anomaly.market_id,
+            anomaly.name,
+            anomaly.listed_at
+        )
+        .execute(&mut *transaction)
+        .await?;
+        let was_latest = sqlx::query!(
+            "DELETE FROM latest_listings WHERE market_id = $1 AND name = $2 AND listed_at = $3;",
+            anomaly.market_id,
+            anomaly.name,
+            anomaly.listed_at
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected()
+            > 0;
+        if was_latest {
+            sqlx::query!(
+                r#"
+                INSERT INTO latest_listings (
+                    market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock,
+                    stock_bracket, listed_at
+                )
+                SELECT market_id, name, mean_price, buy_price, sell_price, demand, demand_bracket, stock,
+                    stock_bracket, listed_at
+                FROM listings
+                WHERE market_id = $1 AND name = $2
+                ORDER BY listed_at DESC
+                LIMIT 1
+                ON CONFLICT DO NOTHING;
+            "#,
+                anomaly.market_id,
+                anomaly.name
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+    }
+
+    set_status(id, "rejected", &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(Some(anomaly))
+}
+
+/// Prints the anomalies waiting for review, or every anomaly if `all` is set
+pub async fn list(all: bool, url: String, pool_size: u32) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    let anomalies = list_anomalies(all, &pool).await?;
+    if anomalies.is_empty() {
+        println!("No price anomalies to review");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL);
+    table.set_header(vec![
+        "ID",
+        "Status",
+        "Commodity",
+        "Station",
+        "System",
+        "Buy",
+        "Sell",
+        "Stock",
+        "Demand",
+        "Listed at",
+        "Reason",
+    ]);
+    for anomaly in anomalies {
+        table.add_row(vec![
+            anomaly.id.to_string(),
+            anomaly.status,
+            anomaly.name,
+            anomaly
+                .station_name
+                .unwrap_or_else(|| anomaly.market_id.to_string()),
+            anomaly.system_name.unwrap_or_default(),
+            anomaly.buy_price.to_string(),
+            anomaly.sell_price.to_string(),
+            anomaly.stock.to_string(),
+            anomaly.demand.to_string(),
+            anomaly.listed_at.to_string(),
+            anomaly.reason,
+        ]);
+    }
+    println!("{}", table);
+    Ok(())
+}
+
+/// Accepts (or rejects) the anomaly with the given ID
+pub async fn review(id: i64, accept: bool, url: String, pool_size: u32) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    let anomaly = if accept {
+        accept_anomaly(id, &pool).await?
+    } else {
+        reject_anomaly(id, &pool).await?
+    }
+    .ok_or_else(|| Error::NotFound(format!("No price anomaly {} waiting for review", id)))?;
+
+    info!(
+        "{} {} {} listing of {} from market {}",
+        if accept { "Accepted" } else { "Rejected" },
+        anomaly.status,
+        anomaly.name,
+        anomaly.listed_at,
+        anomaly.market_id
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALIDATION: PriceValidation = PriceValidation {
+        action: AnomalyAction::Quarantine,
+        threshold: 10.0,
+    };
+
+    fn stats(median: f64, mad: f64, samples: i32) -> PriceStats {
+        PriceStats {
+            name: "gold".to_string(),
+            buy_median: Some(median),
+            buy_mad: Some(mad),
+            buy_samples: samples,
+            sell_median: Some(median),
+            sell_mad: Some(mad),
+            sell_samples: samples,
+        }
+    }
+
+    #[test]
+    fn zero_prices_are_anomalies_without_stats() {
+        assert_eq!(
+            check_price("buy", 0, None, None, 0, 10.0).as_deref(),
+            Some("buy price of 0")
+        );
+        assert!(check_price("sell", -5, Some(1000.0), Some(10.0), 100, 10.0).is_some());
+    }
+
+    #[test]
+    fn sparse_commodities_arent_checked() {
+        assert!(check_price("buy", 1_000_000, None, None, 0, 10.0).is_none());
+        assert!(check_price(
+            "buy",
+            1_000_000,
+            Some(1000.0),
+            Some(10.0),
+            MIN_SAMPLES - 1,
+            10.0
+        )
+        .is_none());
+        assert!(check_price(
+            "buy",
+            1_000_000,
+            Some(1000.0),
+            Some(10.0),
+            MIN_SAMPLES,
+            10.0
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn spread_scales_the_mad() {
+        // spread is 1.4826 * 100, so the threshold is about 1483 from the median
+        assert!(check_price("buy", 2400, Some(1000.0), Some(100.0), 50, 10.0).is_none());
+        assert!(check_price("buy", 2600, Some(1000.0), Some(100.0), 50, 10.0).is_some());
+        assert!(check_price("buy", 1, Some(1000.0), Some(100.0), 50, 10.0).is_none());
+    }
+
+    #[test]
+    fn spread_has_a_floor() {
+        // with no deviation at all the spread is 5% of the median...
+        assert!(check_price("buy", 1400, Some(1000.0), Some(0.0), 50, 10.0).is_none());
+        assert!(check_price("buy", 1600, Some(1000.0), Some(0.0), 50, 10.0).is_some());
+        // ...and at least 1 credit for very cheap commodities
+        assert!(check_price("buy", 14, Some(5.0), Some(0.0), 50, 10.0).is_none());
+        assert!(check_price("buy", 16, Some(5.0), Some(0.0), 50, 10.0).is_some());
+    }
+
+    #[test]
+    fn buy_prices_are_only_checked_with_stock() {
+        let stats = stats(1000.0, 100.0, 50);
+        assert!(VALIDATION
+            .check_prices(0, 1000, 0, 100, Some(&stats))
+            .is_none());
+        assert!(VALIDATION
+            .check_prices(100_000, 1000, 0, 100, Some(&stats))
+            .is_none());
+        let reason = VALIDATION
+            .check_prices(0, 1000, 10, 100, Some(&stats))
+            .unwrap();
+        assert!(reason.starts_with("buy"));
+    }
+
+    #[test]
+    fn sell_prices_are_only_checked_with_demand() {
+        let stats = stats(1000.0, 100.0, 50);
+        assert!(VALIDATION
+            .check_prices(1000, 0, 10, 0, Some(&stats))
+            .is_none());
+        let reason = VALIDATION
+            .check_prices(1000, 0, 10, 5, Some(&stats))
+            .unwrap();
+        assert!(reason.starts_with("sell"));
+    }
+
+    #[test]
+    fn both_sides_are_reported() {
+        let reason = VALIDATION.check_prices(0, 0, 10, 5, None).unwrap();
+        assert_eq!(reason, "buy price of 0, sell price of 0");
+        assert!(VALIDATION.check_prices(1000, 1000, 10, 5, None).is_none());
+    }
+}
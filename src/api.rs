@@ -0,0 +1,479 @@
+// Read-only HTTP JSON API over the EDTear database, so that Kural and the web dashboard don't have to embed
+// their own SQL against our schema. The OpenAPI description is served at /openapi.json.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::{
+    query::{
+        self, Carrier, CarrierPosition, FactionHistoryPoint, Listing, Offer, PadSize, PricePoint,
+        Sphere, Station, StationFilter, System, SystemFaction, TradeKind,
+    },
+    route::{self, JumpRange, Supercharge},
+    stats::{self, Stats},
+    Error, Result,
+};
+
+/// Number of items returned per page if the client doesn't ask for a specific amount
+const DEFAULT_PAGE_SIZE: i64 = 50;
+/// Upper bound on the page size, so a single request can't drag the whole table over the wire
+const MAX_PAGE_SIZE: i64 = 500;
+/// Upper bound on the page size when estimating jumps, since that plots a route per offer
+const MAX_ROUTED_PAGE_SIZE: i64 = 25;
+/// Upper bound on the search radius in light years when estimating jumps, which bounds the length of each route
+const MAX_ROUTED_RADIUS: f64 = 500.0;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "EDTear", description = "Read-only API over the Elite: Dangerous Trade Ear database"),
+    paths(
+        systems_by_name,
+        systems_near,
+        system_stations,
+        system_factions,
+        faction_history,
+        station_market,
+        commodity_best,
+        price_history,
+        carrier,
+        carrier_positions,
+        stats
+    )
+)]
+struct ApiDoc;
+
+/// Error returned by API handlers, rendered as `{"error": "..."}`
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn not_found() -> Self {
+        ApiError(StatusCode::NOT_FOUND, "Not found".to_string())
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Database(sqlx::Error::RowNotFound) => ApiError::not_found(),
+            Error::NotFound(message) => ApiError(StatusCode::NOT_FOUND, message),
+            Error::InvalidArgument(message) => ApiError(StatusCode::BAD_REQUEST, message),
+            error => {
+                warn!("Database error while serving request: {}", error);
+                ApiError(StatusCode::INTERNAL_SERVER_ERROR, "Database error".to_string())
+            }
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(ErrorBody { error: self.1 })).into_response()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct Pagination {
+    /// Maximum number of items to return (default 50, max 500)
+    limit: Option<i64>,
+    /// Number of items to skip
+    offset: Option<i64>,
+}
+
+impl Pagination {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    fn page<T>(&self, items: Vec<T>) -> Page<T> {
+        Page {
+            items,
+            limit: self.limit(),
+            offset: self.offset(),
+        }
+    }
+}
+
+/// One page of results
+#[derive(Serialize, ToSchema)]
+struct Page<T> {
+    items: Vec<T>,
+    limit: i64,
+    offset: i64,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct NameQuery {
+    /// Case-insensitive system name prefix
+    name: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct NearQuery {
+    x: f64,
+    y: f64,
+    z: f64,
+    /// Search radius in light years
+    radius: f64,
+}
+
+impl NearQuery {
+    fn sphere(&self) -> Sphere {
+        Sphere {
+            x: self.x,
+            y: self.y,
+            z: self.z,
+            radius: self.radius,
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct BestQuery {
+    x: f64,
+    y: f64,
+    z: f64,
+    /// Search radius in light years
+    radius: f64,
+    #[param(inline)]
+    kind: TradeKind,
+    /// Only stations offering this service, e.g. Refuel
+    service: Option<String>,
+    /// Only planetary (true) or orbital (false) stations
+    planetary: Option<bool>,
+    /// Only stations with a landing pad of at least this size
+    #[param(inline)]
+    min_pad_size: Option<PadSize>,
+    /// Only fleet carriers (true) or no fleet carriers (false)
+    carriers: Option<bool>,
+    /// Laden jump range in light years. If given, each offer's jumps from the queried point are estimated, which
+    /// plots a route per offer, so pages are capped at 25 offers and the radius at 500 ly.
+    jump_range: Option<f64>,
+    /// Assume every jump is supercharged, for a best case estimate
+    #[param(inline)]
+    supercharge: Option<Supercharge>,
+}
+
+impl BestQuery {
+    fn sphere(&self) -> Sphere {
+        Sphere {
+            x: self.x,
+            y: self.y,
+            z: self.z,
+            radius: self.radius,
+        }
+    }
+
+    fn filter(&self) -> StationFilter {
+        StationFilter {
+            service: self.service.clone(),
+            planetary: self.planetary,
+            min_pad_size: self.min_pad_size,
+            carriers: self.carriers,
+        }
+    }
+
+    /// Checks that the coordinates and ranges are usable, returning a message for the client if not
+    fn validate(&self) -> Result<(), String> {
+        if ![self.x, self.y, self.z, self.radius]
+            .iter()
+            .all(|value| value.is_finite())
+        {
+            return Err("x, y, z and radius must be finite numbers".to_string());
+        }
+        if let Some(range) = self.jump_range {
+            if !range.is_finite() || range <= 0.0 {
+                return Err("jump_range must be a positive number".to_string());
+            }
+            if self.radius > MAX_ROUTED_RADIUS {
+                return Err(format!(
+                    "radius must be at most {} ly when jump_range is given",
+                    MAX_ROUTED_RADIUS
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn jump_range(&self) -> Option<JumpRange> {
+        self.jump_range.map(|range| JumpRange {
+            range,
+            supercharge: self.supercharge,
+        })
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistoryQuery {
+    /// Only return listings at or after this time
+    since: Option<NaiveDateTime>,
+}
+
+/// Search systems by name prefix
+#[utoipa::path(
+    get,
+    path = "/systems",
+    params(NameQuery, Pagination),
+    responses((status = 200, body = Page<System>))
+)]
+async fn systems_by_name(
+    State(pool): State<Pool<Postgres>>,
+    Query(query): Query<NameQuery>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Page<System>>, ApiError> {
+    let systems = query::systems_by_name(&query.name, page.limit(), page.offset(), &pool).await?;
+    Ok(Json(page.page(systems)))
+}
+
+/// Find systems within a radius of a point, nearest first
+#[utoipa::path(
+    get,
+    path = "/systems/near",
+    params(NearQuery, Pagination),
+    responses((status = 200, body = Page<System>))
+)]
+async fn systems_near(
+    State(pool): State<Pool<Postgres>>,
+    Query(query): Query<NearQuery>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Page<System>>, ApiError> {
+    let systems = query::systems_near(query.sphere(), page.limit(), page.offset(), &pool).await?;
+    Ok(Json(page.page(systems)))
+}
+
+/// List the stations in a system, closest to the arrival star first
+#[utoipa::path(
+    get,
+    path = "/systems/{id}/stations",
+    params(("id" = i64, Path, description = "EDSM system ID"), Pagination),
+    responses((status = 200, body = Page<Station>))
+)]
+async fn system_stations(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i64>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Page<Station>>, ApiError> {
+    let stations = query::system_stations(id, page.limit(), page.offset(), &pool).await?;
+    Ok(Json(page.page(stations)))
+}
+
+/// Minor factions present in a system, most influential first
+#[utoipa::path(
+    get,
+    path = "/systems/{id}/factions",
+    params(("id" = i64, Path, description = "EDSM system ID")),
+    responses((status = 200, body = Vec<SystemFaction>))
+)]
+async fn system_factions(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<SystemFaction>>, ApiError> {
+    Ok(Json(query::system_factions(id, &pool).await?))
+}
+
+/// Changes in the influence and states of the factions in a system, newest first
+#[utoipa::path(
+    get,
+    path = "/systems/{id}/factions/history",
+    params(("id" = i64, Path, description = "EDSM system ID"), HistoryQuery, Pagination),
+    responses((status = 200, body = Page<FactionHistoryPoint>))
+)]
+async fn faction_history(
+    State(pool): State<Pool<Postgres>>,
+    Path(id): Path<i64>,
+    Query(query): Query<HistoryQuery>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Page<FactionHistoryPoint>>, ApiError> {
+    let history =
+        query::faction_history(id, query.since, page.limit(), page.offset(), &pool).await?;
+    Ok(Json(page.page(history)))
+}
+
+/// Latest market snapshot for a station
+#[utoipa::path(
+    get,
+    path = "/stations/{market_id}/market",
+    params(("market_id" = i64, Path, description = "Station market ID")),
+    responses(
+        (status = 200, body = Vec<Listing>),
+        (status = 404, body = ErrorBody, description = "No listings for this market")
+    )
+)]
+async fn station_market(
+    State(pool): State<Pool<Postgres>>,
+    Path(market_id): Path<i64>,
+) -> Result<Json<Vec<Listing>>, ApiError> {
+    let listings = query::station_market(market_id, &pool).await?;
+    if listings.is_empty() {
+        return Err(ApiError::not_found());
+    }
+    Ok(Json(listings))
+}
+
+/// Best places to buy or sell a commodity near a point, using each station's latest listing
+#[utoipa::path(
+    get,
+    path = "/commodities/{name}/best",
+    params(("name" = String, Path, description = "EDDN commodity name, e.g. painite"), BestQuery, Pagination),
+    responses(
+        (status = 200, body = Page<Offer>),
+        (status = 400, body = ErrorBody, description = "Invalid coordinates, radius or jump range")
+    )
+)]
+async fn commodity_best(
+    State(pool): State<Pool<Postgres>>,
+    Path(name): Path<String>,
+    Query(query): Query<BestQuery>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Page<Offer>>, ApiError> {
+    query
+        .validate()
+        .map_err(|message| ApiError(StatusCode::BAD_REQUEST, message))?;
+    let jump_range = query.jump_range();
+    let page = match jump_range {
+        Some(_) => Pagination {
+            limit: Some(page.limit().min(MAX_ROUTED_PAGE_SIZE)),
+            offset: page.offset,
+        },
+        None => page,
+    };
+
+    let mut offers = query::best_offers(
+        &name,
+        query.sphere(),
+        query.kind,
+        &query.filter(),
+        page.limit(),
+        page.offset(),
+        &pool,
+    )
+    .await?;
+    if let Some(jump_range) = jump_range {
+        route::estimate_offer_jumps(&mut offers, [query.x, query.y, query.z], &jump_range, &pool)
+            .await?;
+    }
+    Ok(Json(page.page(offers)))
+}
+
+/// Price history of a commodity at a station, newest first
+#[utoipa::path(
+    get,
+    path = "/stations/{market_id}/history/{name}",
+    params(
+        ("market_id" = i64, Path, description = "Station market ID"),
+        ("name" = String, Path, description = "EDDN commodity name, e.g. painite"),
+        HistoryQuery,
+        Pagination
+    ),
+    responses((status = 200, body = Page<PricePoint>))
+)]
+async fn price_history(
+    State(pool): State<Pool<Postgres>>,
+    Path((market_id, name)): Path<(i64, String)>,
+    Query(query): Query<HistoryQuery>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Page<PricePoint>>, ApiError> {
+    let history = query::price_history(
+        market_id,
+        &name,
+        query.since,
+        page.limit(),
+        page.offset(),
+        &pool,
+    )
+    .await?;
+    Ok(Json(page.page(history)))
+}
+
+/// A fleet carrier and where it was last seen
+#[utoipa::path(
+    get,
+    path = "/carriers/{market_id}",
+    params(("market_id" = i64, Path, description = "Carrier market ID")),
+    responses(
+        (status = 200, body = Carrier),
+        (status = 404, body = ErrorBody, description = "No such carrier")
+    )
+)]
+async fn carrier(
+    State(pool): State<Pool<Postgres>>,
+    Path(market_id): Path<i64>,
+) -> Result<Json<Carrier>, ApiError> {
+    Ok(Json(query::carrier(market_id, &pool).await?))
+}
+
+/// Systems a fleet carrier has been seen in, most recent first
+#[utoipa::path(
+    get,
+    path = "/carriers/{market_id}/positions",
+    params(("market_id" = i64, Path, description = "Carrier market ID"), Pagination),
+    responses((status = 200, body = Page<CarrierPosition>))
+)]
+async fn carrier_positions(
+    State(pool): State<Pool<Postgres>>,
+    Path(market_id): Path<i64>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Page<CarrierPosition>>, ApiError> {
+    let positions = query::carrier_positions(market_id, page.limit(), page.offset(), &pool).await?;
+    Ok(Json(page.page(positions)))
+}
+
+/// Summary statistics for the database
+#[utoipa::path(get, path = "/stats", responses((status = 200, body = Stats)))]
+async fn stats(State(pool): State<Pool<Postgres>>) -> Result<Json<Stats>, ApiError> {
+    Ok(Json(stats::compute_stats(&pool).await))
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+pub async fn serve(url: String, pool_size: u32, bind: String) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    let app = Router::new()
+        .route("/openapi.json", get(openapi))
+        .route("/systems", get(systems_by_name))
+        .route("/systems/near", get(systems_near))
+        .route("/systems/{id}/stations", get(system_stations))
+        .route("/systems/{id}/factions", get(system_factions))
+        .route("/systems/{id}/factions/history", get(faction_history))
+        .route("/stations/{market_id}/market", get(station_market))
+        .route("/stations/{market_id}/history/{name}", get(price_history))
+        .route("/commodities/{name}/best", get(commodity_best))
+        .route("/carriers/{market_id}", get(carrier))
+        .route("/carriers/{market_id}/positions", get(carrier_positions))
+        .route("/stats", get(stats))
+        .with_state(pool);
+
+    info!("Serving API on http://{}", bind);
+    let listener = tokio::net::TcpListener::bind(&bind).await?;
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+// Tracks fleet carriers from EDDN. Carriers aren't in EDSM's populated systems dump and move around, so we learn
+// about them from commanders' journals instead: `CarrierJump` and `Docked` events say where a carrier is, and
+// the fcmaterials schemas give its callsign and name. Every carrier also gets a row in `stations` so that its
+// market is stored in the listings like any other.
+
+use chrono::NaiveDateTime;
+use sqlx::{Pool, Postgres, Transaction};
+
+use crate::Error;
+
+/// Station type of fleet carriers in journal events
+pub const CARRIER_STATION_TYPE: &str = "FleetCarrier";
+
+/// Where a carrier was seen, from a journal event
+#[derive(Debug, Clone)]
+pub struct CarrierLocation {
+    pub market_id: i64,
+    pub callsign: String,
+    pub system_id64: i64,
+    pub system_name: String,
+    /// Position of the system, if the event has it. Otherwise it's looked up from the systems we know.
+    pub coords: Option<[f64; 3]>,
+    pub seen_at: NaiveDateTime,
+}
+
+/// Inserts the carrier and its station if we haven't seen it before
+async fn ensure_carrier(
+    market_id: i64,
+    callsign: &str,
+    seen_at: NaiveDateTime,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO carriers (market_id, callsign, updated_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (market_id) DO UPDATE SET
+            callsign = EXCLUDED.callsign,
+            updated_at = GREATEST(carriers.updated_at, EXCLUDED.updated_at);
+    "#,
+        market_id,
+        callsign,
+        seen_at
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // carriers from EDSM's stations dump already have a station
+    sqlx::query!(
+        r#"
+        INSERT INTO stations (id, name, market_id, station_type, planetary, inferred_pad_size)
+        VALUES ($1, $2, $1, 'Drake-Class Carrier', FALSE, 'L')
+        ON CONFLICT DO NOTHING;
+    "#,
+        market_id,
+        callsign
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// Records a carrier's callsign and name, from an fcmaterials message
+pub async fn update_carrier_identity(
+    market_id: i64,
+    callsign: &str,
+    name: &str,
+    seen_at: NaiveDateTime,
+    pool: &Pool<Postgres>,
+) -> Result<(), Error> {
+    let mut transaction = pool.begin().await?;
+    ensure_carrier(market_id, callsign, seen_at, &mut transaction).await?;
+    sqlx::query!(
+        "UPDATE carriers SET name = $2 WHERE market_id = $1;",
+        market_id,
+        name
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Moves a carrier to where it was seen, unless we already know where it was at a later time. A position is
+/// added to its history whenever it's seen in a different system. Returns whether the carrier moved.
+pub async fn update_carrier_location(
+    location: &CarrierLocation,
+    pool: &Pool<Postgres>,
+) -> Result<bool, Error> {
+    let [x, y, z] = location.coords.map_or([None; 3], |coords| coords.map(Some));
+    let mut transaction = pool.begin().await?;
+    ensure_carrier(
+        location.market_id,
+        &location.callsign,
+        location.seen_at,
+        &mut transaction,
+    )
+    .await?;
+
+    let moved = sqlx::query!(
+        r#"
+        INSERT INTO carrier_positions (market_id, system_id64, system_name, coords, seen_at)
+        SELECT $1, $2, $3,
+            COALESCE(ST_MakePoint($4, $5, $6), (SELECT coords FROM systems WHERE id64 = $2 LIMIT 1)),
+            $7
+        WHERE NOT EXISTS (
+            SELECT 1 FROM carriers
+            WHERE market_id = $1 AND (system_id64 = $2 OR located_at > $7)
+        )
+        ON CONFLICT DO NOTHING;
+    "#,
+        location.market_id,
+        location.system_id64,
+        location.system_name,
+        x,
+        y,
+        z,
+        location.seen_at
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected()
+        > 0;
+
+    sqlx::query!(
+        r#"
+        UPDATE carriers SET
+            system_id64 = $2,
+            system_name = $3,
+            coords = COALESCE(
+                ST_MakePoint($4, $5, $6),
+                (SELECT coords FROM systems WHERE id64 = $2 LIMIT 1),
+                CASE WHEN system_id64 = $2 THEN coords END
+            ),
+            located_at = $7
+        WHERE market_id = $1 AND (located_at IS NULL OR located_at <= $7);
+    "#,
+        location.market_id,
+        location.system_id64,
+        location.system_name,
+        x,
+        y,
+        z,
+        location.seen_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+    Ok(moved)
+}
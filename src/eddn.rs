@@ -1,12 +1,24 @@
 use std::{
-    process::exit, sync::{atomic::AtomicU32, Arc, Mutex}, thread, time::Duration
+    collections::HashMap, process::exit, sync::{atomic::AtomicU32, Arc, Mutex}, thread, time::Duration
 };
 
-use color_eyre::eyre::Result;
+use chrono::NaiveDateTime;
 use lazy_static::lazy_static;
 use log::{error, info, warn};
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use tokio::sync::watch;
+use tokio::sync::broadcast;
+
+use crate::{
+    alerts,
+    anomaly::{self, AnomalyAction, PriceValidation},
+    carriers::{self, CarrierLocation},
+    config::ListenerConfig,
+    factions::{self, FactionPresence, Powerplay, SystemFactions},
+    latest, maintenance,
+    notify::{self, MarketNotification},
+    stream::{self, CommodityUpdate, MarketUpdate},
+    Error, Result,
+};
 
 // Ingests data from the Elite: Dangerous Data Network
 
@@ -15,127 +27,509 @@ lazy_static! {
     static ref WATCHDOG_ADDED: Arc<Mutex<u32>> = Arc::new(Mutex::new(0));
 }
 
-/// Returns whether or not the given market ID has listings in the last 1 hour
-async fn has_listings_1h(market_id: i64, pool: &Pool<Postgres>) -> bool {
+/// Returns whether or not the given market ID has listings in the last `minutes` minutes
+async fn has_recent_listings(market_id: i64, minutes: u32, pool: &Pool<Postgres>) -> bool {
     let result = sqlx::query!(
         r#"
         SELECT * FROM listings
         WHERE market_id = $1
-        AND listed_at >= NOW() - INTERVAL '1 hour'
+        AND listed_at >= NOW() - make_interval(mins => $2)
         LIMIT 1;
     "#,
-        market_id
+        market_id,
+        minutes as i32
     )
     .fetch_one(pool)
     .await;
     result.is_ok()
 }
 
-/// Runs every 1 hour to ensure that the application is still receiving data. If it is not, quits
+/// Runs every `interval` to ensure that the application is still receiving data. If it is not, quits
 /// the whole application (Docker will restart us)
-fn watchdog() {
+fn watchdog(interval: Duration) {
     loop {
-        thread::sleep(Duration::from_hours(1));
+        thread::sleep(interval);
         let mut count = WATCHDOG_ADDED.lock().unwrap();
         if *count > 0 {
             // watchdog passed, reset the count and wait again
             info!("Watchdog OK");
             *count = 0;
         } else {
-            error!("Watchdog FAILED! 0 items added in the last hour. Quitting now!");
+            error!(
+                "Watchdog FAILED! 0 items added in the last {} minutes. Quitting now!",
+                interval.as_secs() / 60
+            );
             // docker will restart us (hopefully)
             exit(1);
         }
     }
 }
 
-pub async fn listen(url: String) -> Result<()> {
+/// Looks up the position of the system containing the given market, if we know about it
+async fn market_coords(market_id: i64, pool: &Pool<Postgres>) -> Option<[f64; 3]> {
+    let result = sqlx::query!(
+        r#"
+        SELECT ST_X(coords) AS "x!", ST_Y(coords) AS "y!", ST_Z(coords) AS "z!"
+        FROM (
+            SELECT COALESCE(c.coords, sys.coords) AS coords
+            FROM stations stat
+            LEFT JOIN systems sys ON sys.id = stat.system_id
+            LEFT JOIN carriers c ON c.market_id = stat.market_id
+            WHERE stat.market_id = $1
+        ) station
+        WHERE coords IS NOT NULL;
+    "#,
+        market_id
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(row) => row.map(|row| [row.x, row.y, row.z]),
+        Err(error) => {
+            warn!("Failed to look up coordinates for market {}: {}", market_id, error);
+            None
+        }
+    }
+}
+
+/// Returns where a fleet carrier is, if the journal event says so
+fn carrier_location(journal: &eddn::journal::Journal) -> Option<CarrierLocation> {
+    let seen_at = journal.timestamp.naive_utc();
+    match &journal.event {
+        // the commander is docked on the carrier as it jumps
+        eddn::journal::Event::CarrierJump(jump)
+            if jump.station_type.as_deref() == Some(carriers::CARRIER_STATION_TYPE) =>
+        {
+            Some(CarrierLocation {
+                market_id: jump.market_id?,
+                callsign: jump.station_name.clone()?,
+                system_id64: jump.system_address,
+                system_name: jump.star_system.clone(),
+                coords: Some(jump.star_pos),
+                seen_at,
+            })
+        }
+        eddn::journal::Event::Docked(docked)
+            if docked.station_type == carriers::CARRIER_STATION_TYPE =>
+        {
+            Some(CarrierLocation {
+                market_id: docked.market_id,
+                callsign: docked.station_name.clone(),
+                system_id64: docked.system_address,
+                system_name: docked.star_system.clone(),
+                coords: None,
+                seen_at,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Converts a faction from a journal event's Factions array
+fn faction_presence(faction: &eddn::journal::Faction) -> FactionPresence {
+    let states = |states: &Option<Vec<eddn::journal::FactionState>>| {
+        states
+            .iter()
+            .flatten()
+            .map(|state| state.state.clone())
+            .collect()
+    };
+    FactionPresence {
+        faction: faction.name.clone(),
+        influence: faction.influence as f32,
+        state: factions::state_name(Some(&faction.faction_state)),
+        active_states: states(&faction.active_states),
+        pending_states: states(&faction.pending_states),
+        recovering_states: states(&faction.recovering_states),
+        allegiance: Some(faction.allegiance.clone()),
+        government: Some(faction.government.clone()),
+        happiness: faction.happiness.clone(),
+    }
+}
+
+/// Returns the factions and powerplay state of the system the commander is in, if the journal event says so
+/// (FSDJump and Location events only have them in populated systems)
+fn system_factions(journal: &eddn::journal::Journal) -> Option<SystemFactions> {
+    let (
+        star_system,
+        system_address,
+        factions,
+        system_faction,
+        powers,
+        powerplay_state,
+        controlling_power,
+    ) = match &journal.event {
+        eddn::journal::Event::FSDJump(jump) => (
+            &jump.star_system,
+            jump.system_address,
+            &jump.factions,
+            &jump.system_faction,
+            &jump.powers,
+            &jump.powerplay_state,
+            &jump.controlling_power,
+        ),
+        eddn::journal::Event::Location(location) => (
+            &location.star_system,
+            location.system_address,
+            &location.factions,
+            &location.system_faction,
+            &location.powers,
+            &location.powerplay_state,
+            &location.controlling_power,
+        ),
+        _ => return None,
+    };
+    let has_powerplay =
+        controlling_power.is_some() || powers.is_some() || powerplay_state.is_some();
+    Some(SystemFactions {
+        system_id64: system_address,
+        system_name: star_system.clone(),
+        controlling_faction: system_faction.as_ref().map(|faction| faction.name.clone()),
+        // the journal leaves these out of systems without powerplay, in which case we keep what we know
+        powerplay: has_powerplay.then(|| Powerplay {
+            controlling_power: controlling_power.clone(),
+            powers: powers.clone().unwrap_or_default(),
+            powerplay_state: powerplay_state.clone(),
+        }),
+        factions: factions.as_ref()?.iter().map(faction_presence).collect(),
+        seen_at: journal.timestamp.naive_utc(),
+    })
+}
+
+/// Handles a message from EDDN. Market updates are checked against `validation` and inserted by `handle_market`;
+/// journal events and fcmaterials
+/// messages update the fleet carriers we track, and journal events also update system factions. Returns the
+/// market update if one was inserted (without coordinates), or None otherwise.
+pub async fn handle_envelope(
+    envelope: eddn::Envelope,
+    dedupe_minutes: u32,
+    validation: &PriceValidation,
+    pool: &Pool<Postgres>,
+) -> crate::Result<Option<MarketUpdate>> {
+    let time = envelope.header.gateway_timestamp.naive_utc();
+    match envelope.message {
+        eddn::Message::Commodity(commodity) => {
+            handle_market(commodity.event, time, dedupe_minutes, validation, pool).await
+        }
+        eddn::Message::Journal(journal) => {
+            if let Some(location) = carrier_location(&journal) {
+                if carriers::update_carrier_location(&location, pool).await? {
+                    info!(
+                        "Carrier {} moved to {}",
+                        location.callsign, location.system_name
+                    );
+                }
+            }
+            if let Some(system) = system_factions(&journal) {
+                if factions::update_system_factions(&system, pool).await? {
+                    info!(
+                        "Updated {} factions in {}",
+                        system.factions.len(),
+                        system.system_name
+                    );
+                }
+            }
+            Ok(None)
+        }
+        eddn::Message::FcMaterialsJournal(materials)
+        | eddn::Message::FcMaterialsCapi(materials) => {
+            carriers::update_carrier_identity(
+                materials.market_id,
+                &materials.carrier_id,
+                &materials.carrier_name,
+                time,
+                pool,
+            )
+            .await?;
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Handles a market update received at `time`: if the station hasn't been updated in the last `dedupe_minutes`,
+/// inserts its listings, updates the latest listings and publishes a notification. Listings with anomalous prices
+/// are recorded for review, and skipped if they're to be quarantined. Returns the update if it was inserted, or
+/// None if it was skipped.
+async fn handle_market(
+    market: eddn::Market,
+    time: NaiveDateTime,
+    dedupe_minutes: u32,
+    validation: &PriceValidation,
+    pool: &Pool<Postgres>,
+) -> crate::Result<Option<MarketUpdate>> {
+    let market_id = market.market_id;
+
+    // check if this system already has a recent record (to save disk space)
+    if has_recent_listings(market_id, dedupe_minutes, pool).await {
+        info!(
+            "Station {} in {} already updated in the last {} minutes, skipping",
+            market.station_name, market.system_name, dedupe_minutes
+        );
+        return Ok(None);
+    }
+
+    let stats = if validation.action == AnomalyAction::Off {
+        HashMap::new()
+    } else {
+        let names: Vec<String> = market
+            .commodities
+            .iter()
+            .map(|commodity| commodity.name.clone())
+            .collect();
+        anomaly::price_stats(&names, pool).await?
+    };
+
+    // insert into the DB
+    let mut transaction = pool.begin().await?;
+    let mut committed = Vec::with_capacity(market.commodities.len());
+
+    for commodity in market.commodities {
+        if validation.action != AnomalyAction::Off {
+            if let Some(reason) = validation.check(&commodity, stats.get(&commodity.name)) {
+                warn!(
+                    "Anomalous {} listing for {} in {}: {}",
+                    commodity.name, market.station_name, market.system_name, reason
+                );
+                anomaly::record_anomaly(
+                    market_id,
+                    &commodity,
+                    time,
+                    &reason,
+                    validation.action,
+                    &mut transaction,
+                )
+                .await?;
+                if validation.action == AnomalyAction::Quarantine {
+                    continue;
+                }
+            }
+        }
+
+        // also keep the market's current prices up to date, unless we already have a
+        // newer snapshot
+        let result = sqlx::query!(
+            r#"
+            WITH inserted AS (
+                INSERT INTO listings (
+                    market_id,
+                    name,
+                    mean_price,
+                    buy_price,
+                    sell_price,
+                    demand,
+                    demand_bracket,
+                    stock,
+                    stock_bracket,
+                    listed_at
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8, $9, $10
+                )
+                RETURNING *
+            )
+            INSERT INTO latest_listings (
+                market_id,
+                name,
+                mean_price,
+                buy_price,
+                sell_price,
+                demand,
+                demand_bracket,
+                stock,
+                stock_bracket,
+                listed_at
+            )
+            SELECT market_id, name, mean_price, buy_price, sell_price, demand,
+                demand_bracket, stock, stock_bracket, listed_at
+            FROM inserted
+            ON CONFLICT (market_id, name) DO UPDATE SET
+                mean_price = EXCLUDED.mean_price,
+                buy_price = EXCLUDED.buy_price,
+                sell_price = EXCLUDED.sell_price,
+                demand = EXCLUDED.demand,
+                demand_bracket = EXCLUDED.demand_bracket,
+                stock = EXCLUDED.stock,
+                stock_bracket = EXCLUDED.stock_bracket,
+                listed_at = EXCLUDED.listed_at
+            WHERE latest_listings.listed_at <= EXCLUDED.listed_at;
+        "#,
+            market_id,
+            commodity.name,
+            commodity.mean_price,
+            commodity.buy_price,
+            commodity.sell_price,
+            commodity.demand,
+            commodity.demand_bracket,
+            commodity.stock,
+            commodity.stock_bracket,
+            time
+        )
+        .execute(&mut *transaction)
+        .await;
+
+        match result {
+            Ok(_) => {
+                committed.push(CommodityUpdate {
+                    name: commodity.name,
+                    mean_price: commodity.mean_price,
+                    buy_price: commodity.buy_price,
+                    sell_price: commodity.sell_price,
+                    demand: commodity.demand,
+                    stock: commodity.stock,
+                });
+            }
+            Err(error) => {
+                // cancel the entire update
+                transaction.rollback().await?;
+                return Err(Error::Listing {
+                    commodity: commodity.name,
+                    station: market.station_name,
+                    system: market.system_name,
+                    source: error,
+                });
+            }
+        }
+    }
+    transaction.commit().await?;
+    *WATCHDOG_ADDED.lock().unwrap() += committed.len() as u32;
+
+    info!(
+        "Inserted market data for {} in {}",
+        market.station_name, market.system_name
+    );
+
+    if let Err(error) = latest::prune_market(market_id, time, pool).await {
+        warn!(
+            "Failed to prune latest listings for {} in {}: {}",
+            market.station_name, market.system_name, error
+        );
+    }
+
+    let notification = MarketNotification {
+        market_id,
+        system: market.system_name.clone(),
+        station: market.station_name.clone(),
+        timestamp: time,
+        commodity_count: committed.len(),
+    };
+    if let Err(error) = notify::notify_market_updated(&notification, pool).await {
+        warn!(
+            "Failed to notify update for {} in {}: {}",
+            market.station_name, market.system_name, error
+        );
+    }
+
+    Ok(Some(MarketUpdate {
+        market_id,
+        coords: None,
+        system_name: market.system_name,
+        station_name: market.station_name,
+        listed_at: time,
+        commodities: committed,
+    }))
+}
+
+/// Evaluates the price alert rules against a market update and delivers the ones that fire, in the background so
+/// that neither the evaluation nor slow webhooks hold up the listener (or the stream's subscribers)
+fn fire_alerts(update: MarketUpdate, webhooks: reqwest::Client, pool: Pool<Postgres>) {
+    tokio::spawn(async move {
+        let fired = match alerts::evaluate_alerts(&update, &pool).await {
+            Ok(fired) => fired,
+            Err(error) => {
+                warn!(
+                    "Failed to evaluate price alerts for {} in {}: {}",
+                    update.station_name, update.system_name, error
+                );
+                return;
+            }
+        };
+        for alert in fired {
+            let webhooks = webhooks.clone();
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                if let Err(error) = alerts::deliver_alert(&alert, &webhooks, &pool).await {
+                    warn!("Failed to record price alert delivery: {}", error);
+                }
+            });
+        }
+    });
+}
+
+pub async fn listen(url: String, pool_size: u32, config: ListenerConfig) -> Result<()> {
     info!("Setting up PostgreSQL pool on {}", url);
     let var_name = PgPoolOptions::new();
-    let pool = var_name.max_connections(8).connect(&url).await?;
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    // make sure there's a partition to insert into, even if maintenance isn't being run
+    info!("Creating listings partitions");
+    maintenance::create_partitions(maintenance::MONTHS_AHEAD, &pool).await?;
+    let partitions_pool = pool.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_hours(24)).await;
+            if let Err(error) =
+                maintenance::create_partitions(maintenance::MONTHS_AHEAD, &partitions_pool).await
+            {
+                warn!("Failed to create listings partitions: {}", error);
+            }
+        }
+    });
+
+    let validation = PriceValidation {
+        action: config.anomaly_action,
+        threshold: config.anomaly_threshold,
+    };
+    if validation.action != AnomalyAction::Off {
+        info!("Computing commodity price distributions");
+        anomaly::refresh_price_stats(&pool).await?;
+        let stats_pool = pool.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_hours(anomaly::STATS_REFRESH_HOURS)).await;
+                if let Err(error) = anomaly::refresh_price_stats(&stats_pool).await {
+                    warn!("Failed to compute commodity price distributions: {}", error);
+                }
+            }
+        });
+    }
 
     info!("Starting watchdog");
-    thread::spawn(|| {
-        watchdog();
+    let watchdog_interval = Duration::from_mins(config.watchdog_minutes as u64);
+    thread::spawn(move || {
+        watchdog(watchdog_interval);
     });
 
+    let webhooks = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.webhook_timeout_seconds))
+        .build()?;
+
+    let (updates, _) = broadcast::channel::<MarketUpdate>(stream::STREAM_CAPACITY);
+    if let Some(bind) = config.stream_bind {
+        let sender = updates.clone();
+        tokio::spawn(async move {
+            if let Err(error) = stream::serve_stream(bind, sender).await {
+                error!("Market update stream failed: {}", error);
+            }
+        });
+    }
+
     for env in eddn::subscribe(eddn::URL) {
         match env {
             Ok(envelope) => {
-                if let eddn::Message::Commodity(commodity) = envelope.message {
-                    let market = commodity.event;
-                    let market_id = market.market_id;
-
-                    // check if this system already has a record in the last 1 hour (to save disk
-                    // space)
-                    if has_listings_1h(market_id, &pool).await {
-                        info!(
-                            "Station {} in {} already updated in the last 1 hour, skipping",
-                            market.station_name, market.system_name
-                        );
-                        continue;
-                    }
-
-                    // insert into the DB
-                    let transaction = pool.begin().await?;
-                    let time = envelope.header.gateway_timestamp.naive_utc();
-                    let mut success = true;
-
-                    for commodity in market.commodities {
-                        let result = sqlx::query!(
-                            r#"
-                            INSERT INTO listings (
-                                market_id,
-                                name,
-                                mean_price,
-                                buy_price,
-                                sell_price,
-                                demand,
-                                demand_bracket,
-                                stock,
-                                stock_bracket,
-                                listed_at
-                            ) VALUES (
-                                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10
-                            );
-                        "#,
-                            market_id,
-                            commodity.name,
-                            commodity.mean_price,
-                            commodity.buy_price,
-                            commodity.sell_price,
-                            commodity.demand,
-                            commodity.demand_bracket,
-                            commodity.stock,
-                            commodity.stock_bracket,
-                            time
-                        )
-                        .execute(&pool)
-                        .await;
-
-                        match result {
-                            Ok(_) => {
-                                let mut count = WATCHDOG_ADDED.lock().unwrap();
-                                *count += 1;
-                            }
-                            Err(error) => {
-                                warn!(
-                                    "Failed to insert commodity {} for station {} in {}: {}",
-                                    commodity.name, market.station_name, market.system_name, error
-                                );
-                                // cancel the entire update
-                                success = false;
-                                break;
-                            }
+                match handle_envelope(envelope, config.dedupe_minutes, &validation, &pool).await {
+                    Ok(Some(mut update)) => {
+                        fire_alerts(update.clone(), webhooks.clone(), pool.clone());
+                        // only bother looking up the position if someone is listening
+                        if updates.receiver_count() > 0 {
+                            update.coords = market_coords(update.market_id, &pool).await;
+                            // an error here just means every client disconnected in the meantime
+                            let _ = updates.send(update);
                         }
                     }
-                    transaction.commit().await?;
-
-                    if success {
-                        info!(
-                            "Inserted market data for {} in {}",
-                            market.station_name, market.system_name
-                        );
+                    Ok(None) => {}
+                    Err(error) => {
+                        warn!("{}", error);
                     }
                 }
             }
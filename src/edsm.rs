@@ -1,24 +1,98 @@
-use color_eyre::eyre::Result;
+use chrono::DateTime;
 use edsm_dumps_model::model::{
+    body::Body,
     station::{Station, StationType},
-    system_populated::SystemPopulated,
+    system::SystemWithCoordinates,
+    system_populated::{FactionState, SystemPopulated},
 };
 use futures::StreamExt;
-use indicatif::ProgressIterator;
+use indicatif::{ProgressBar, ProgressIterator};
 use log::{info, warn};
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use std::{fs::File, io::BufReader};
+use serde::de::DeserializeOwned;
+use sqlx::{Acquire, Pool, Postgres};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use crate::{
+    factions::{self, FactionPresence, SystemFactions},
+    query::PadSize,
+    Result,
+};
 
 // Ingests data from Elite: Dangerous Star Map data dumps
 
-async fn read_systems(systems_json_path: std::path::PathBuf, pool: &Pool<Postgres>) -> Result<()> {
+/// Number of rows from EDSM's huge dumps (full systems and bodies) to COPY into the database per transaction
+const COPY_CHUNK_ROWS: usize = 100_000;
+
+/// Guesses the largest landing pad of a station from its type, for stations that Spansh doesn't have pad counts
+/// for. Returns None if the type doesn't tell.
+fn inferred_pad_size(typ: StationType) -> Option<PadSize> {
+    match typ {
+        StationType::Outpost => Some(PadSize::Medium),
+        StationType::CoriolisStarport
+        | StationType::OrbisStarport
+        | StationType::OcellusStarport
+        | StationType::AsteroidBase
+        | StationType::PlanetaryPort
+        | StationType::PlanetaryOutpost
+        | StationType::MegaShip
+        | StationType::FleetCarrier => Some(PadSize::Large),
+        // layouts range from a single small pad to several large ones, and EDSM's dump doesn't have the
+        // layout, so leave it to Spansh's pad counts rather than store a guess
+        StationType::OdysseySettlement => None,
+    }
+}
+
+/// Returns the factions of a populated system, if EDSM has them. EDSM's dump has no powerplay state.
+fn system_factions(system: &SystemPopulated) -> Option<SystemFactions> {
+    let factions = system
+        .factions
+        .as_ref()
+        .filter(|factions| !factions.is_empty())?;
+    // the system's date is when EDSM first saw it, so use the most recent faction update instead
+    let seen_at = factions.iter().map(|faction| faction.last_update).max()?;
+    let states = |states: &[FactionState]| states.iter().map(|state| state.state.clone()).collect();
+    Some(SystemFactions {
+        system_id64: system.id64? as i64,
+        system_name: system.name.clone(),
+        controlling_faction: system
+            .controlling_faction
+            .as_ref()
+            .map(|faction| faction.name.clone()),
+        powerplay: None,
+        factions: factions
+            .iter()
+            .map(|faction| FactionPresence {
+                faction: faction.name.clone(),
+                influence: faction.influence,
+                state: factions::state_name(faction.state.as_deref()),
+                active_states: states(&faction.active_states),
+                pending_states: states(&faction.pending_states),
+                recovering_states: states(&faction.recovering_states),
+                allegiance: faction.allegiance.clone(),
+                government: faction.government.clone(),
+                happiness: faction.happiness.clone(),
+            })
+            .collect(),
+        seen_at: DateTime::from_timestamp(seen_at, 0)?.naive_utc(),
+    })
+}
+
+async fn read_systems(
+    systems_json_path: std::path::PathBuf,
+    batch_size: usize,
+    pool: &Pool<Postgres>,
+) -> Result<()> {
     info!("Parsing systems JSON");
     let system_file = File::open(systems_json_path)?;
     let system_reader = BufReader::new(system_file);
     let systems: Vec<SystemPopulated> = serde_json::from_reader(system_reader)?;
     info!("Parsed {} systems", systems.len());
 
-    let chunks = systems.chunks(2048);
+    let chunks = systems.chunks(batch_size);
     info!("Will insert {} chunks", chunks.len());
 
     info!("Inserting into PostgreSQL");
@@ -28,20 +102,27 @@ async fn read_systems(systems_json_path: std::path::PathBuf, pool: &Pool<Postgre
 
         futures::stream::iter(chunk)
             .for_each(|system| async move {
+                // re-running the import fills in id64 for systems imported before we stored it, and marks
+                // systems from the full dump as populated once they are
                 let result = sqlx::query!(
                     r#"
                         INSERT INTO systems (
-                            id, name, date, coords
+                            id, name, date, coords, id64, populated
                         ) VALUES (
-                            $1, $2, $3, ST_MakePoint($4, $5, $6)
-                        );
+                            $1, $2, $3, ST_MakePoint($4, $5, $6), $7, TRUE
+                        )
+                        ON CONFLICT (id) DO UPDATE SET
+                            id64 = COALESCE(systems.id64, EXCLUDED.id64),
+                            populated = TRUE
+                        WHERE systems.id64 IS NULL OR NOT systems.populated;
                         "#,
                     system.id as i64,
                     system.name,
                     system.date.naive_utc(),
                     system.coords.x as f64,
                     system.coords.y as f64,
-                    system.coords.z as f64
+                    system.coords.z as f64,
+                    system.id64.map(|v| v as i64)
                 )
                 .execute(pool)
                 .await;
@@ -59,6 +140,15 @@ async fn read_systems(systems_json_path: std::path::PathBuf, pool: &Pool<Postgre
                         }
                     }
                 }
+
+                if let Some(factions) = system_factions(system) {
+                    if let Err(error) = factions::update_system_factions(&factions, pool).await {
+                        warn!(
+                            "Failed to update factions of system id {} name {}: {}",
+                            system.id, system.name, error
+                        );
+                    }
+                }
             })
             .await;
 
@@ -68,8 +158,158 @@ async fn read_systems(systems_json_path: std::path::PathBuf, pool: &Pool<Postgre
     Ok(())
 }
 
+/// Escapes a value for COPY's text format
+fn copy_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Formats an optional value for COPY's text format, where NULL is `\N`
+fn copy_nullable<T: Display>(value: Option<T>) -> String {
+    value.map_or("\\N".to_string(), |value| copy_escape(&value.to_string()))
+}
+
+/// How to bulk load one of EDSM's huge dumps. Rows are COPYed into a temporary table first and then moved into the
+/// real table by `upsert`, so that rows we already have can be skipped or merged, which COPY can't do by itself.
+struct BulkLoad {
+    /// Creates the temporary table, which must be ON COMMIT DELETE ROWS
+    create: &'static str,
+    /// COPY ... FROM STDIN into the temporary table, in text format
+    copy: &'static str,
+    upsert: &'static str,
+}
+
+/// COPYs a chunk of rows into the database in one transaction. Returns the number of rows upserted.
+async fn copy_chunk(
+    load: &BulkLoad,
+    rows: &str,
+    connection: &mut sqlx::PgConnection,
+) -> Result<u64> {
+    let mut transaction = connection.begin().await?;
+    let mut copy = transaction.copy_in_raw(load.copy).await?;
+    copy.send(rows.as_bytes()).await?;
+    copy.finish().await?;
+
+    let upserted = sqlx::query(load.upsert)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    transaction.commit().await?;
+    Ok(upserted)
+}
+
+/// Streams one of EDSM's huge dumps into the database. They're too big to parse into memory at once like the
+/// other dumps, so they're read line by line (the dumps have one record per line) and bulk loaded with COPY,
+/// `COPY_CHUNK_ROWS` rows per transaction. `row` formats a record as a line of COPY's text format. Lines that
+/// can't be parsed are logged and skipped, rather than abandoning an import that may have been running for hours.
+/// Returns the number of records read, the number upserted and the number skipped.
+async fn stream_dump<T: DeserializeOwned>(
+    json_path: std::path::PathBuf,
+    load: &BulkLoad,
+    row: impl Fn(T) -> String,
+    pool: &Pool<Postgres>,
+) -> Result<(usize, u64, usize)> {
+    let file = File::open(json_path)?;
+    let progress = ProgressBar::new(file.metadata()?.len());
+    let reader = BufReader::new(progress.wrap_read(file));
+
+    let mut connection = pool.acquire().await?;
+    sqlx::query(load.create).execute(&mut *connection).await?;
+
+    let mut rows = String::new();
+    let mut chunk_rows = 0;
+    let mut total = 0;
+    let mut upserted = 0;
+    let mut skipped = 0;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim().trim_end_matches(',');
+        if line.is_empty() || line == "[" || line == "]" {
+            continue;
+        }
+        let record = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(error) => {
+                warn!("Skipping line {}, failed to parse: {}", number + 1, error);
+                skipped += 1;
+                continue;
+            }
+        };
+        rows.push_str(&row(record));
+        rows.push('\n');
+
+        chunk_rows += 1;
+        if chunk_rows == COPY_CHUNK_ROWS {
+            upserted += copy_chunk(load, &rows, &mut connection).await?;
+            total += chunk_rows;
+            rows.clear();
+            chunk_rows = 0;
+        }
+    }
+    if chunk_rows > 0 {
+        upserted += copy_chunk(load, &rows, &mut connection).await?;
+        total += chunk_rows;
+    }
+    progress.finish();
+    Ok((total, upserted, skipped))
+}
+
+/// Streams EDSM's full systems dump (tens of millions of systems) into the database. The GIST index on coords is
+/// kept up to date as we go rather than dropped and rebuilt, so routes can still be plotted while the import
+/// runs, and re-running it only has to load new systems.
+async fn read_systems_with_coordinates(
+    systems_json_path: std::path::PathBuf,
+    pool: &Pool<Postgres>,
+) -> Result<()> {
+    info!("Streaming systems with coordinates JSON");
+    let load = BulkLoad {
+        create: r#"
+            CREATE TEMPORARY TABLE IF NOT EXISTS systems_import (
+                id BIGINT, id64 BIGINT, name TEXT, date TIMESTAMP, x FLOAT8, y FLOAT8, z FLOAT8
+            ) ON COMMIT DELETE ROWS;
+        "#,
+        copy: "COPY systems_import (id, id64, name, date, x, y, z) FROM STDIN;",
+        // systems we already have (e.g. from the populated dump) are left alone, apart from filling in id64
+        upsert: r#"
+            INSERT INTO systems (id, name, date, coords, id64, populated)
+            SELECT id, name, date, ST_MakePoint(x, y, z), id64, FALSE
+            FROM systems_import
+            ON CONFLICT (id) DO UPDATE SET id64 = EXCLUDED.id64
+            WHERE systems.id64 IS NULL AND EXCLUDED.id64 IS NOT NULL;
+        "#,
+    };
+    let (total, upserted, skipped) = stream_dump(
+        systems_json_path,
+        &load,
+        |system: SystemWithCoordinates| {
+            format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                system.id,
+                copy_nullable(system.id64),
+                copy_escape(&system.name),
+                system.date.naive_utc(),
+                system.coords.x,
+                system.coords.y,
+                system.coords.z
+            )
+        },
+        pool,
+    )
+    .await?;
+
+    info!(
+        "Read {} systems, inserted or updated {} of them, skipped {} unparseable lines",
+        total, upserted, skipped
+    );
+    Ok(())
+}
+
 async fn read_stations(
     stations_json_path: std::path::PathBuf,
+    batch_size: usize,
     pool: &Pool<Postgres>,
 ) -> Result<()> {
     info!("Parsing stations JSON");
@@ -78,7 +318,7 @@ async fn read_stations(
     let stations: Vec<Station> = serde_json::from_reader(stations_reader)?;
     info!("Parsed {} stations", stations.len());
 
-    let chunks = stations.chunks(2048);
+    let chunks = stations.chunks(batch_size);
     info!("Will insert {} chunks", chunks.len());
 
     info!("Inserting into PostgreSQL");
@@ -87,19 +327,26 @@ async fn read_stations(
         let transaction = pool.begin().await?;
         futures::stream::iter(chunk)
             .for_each(|station| async move {
+                // re-running the import fills in the inferred pad size and body for stations imported before
+                // we stored them
                 let result = sqlx::query!(
                     r#"
                     INSERT INTO stations (
-                        id, distance_to_arrival, name, market_id, system_id, landing_pad
+                        id, distance_to_arrival, name, market_id, system_id, inferred_pad_size, body_id
                     ) VALUES (
-                        $1, $2, $3, $4, $5, NULL
-                    );
+                        $1, $2, $3, $4, $5, $6, $7
+                    )
+                    ON CONFLICT (id) DO UPDATE SET
+                        inferred_pad_size = EXCLUDED.inferred_pad_size,
+                        body_id = EXCLUDED.body_id;
                 "#,
                     station.id as i64,
                     station.distance_to_arrival,
                     station.name,
                     station.market_id.map(|v| v as i64),
                     station.system_id.map(|v| v as i64),
+                    station.typ.and_then(inferred_pad_size) as Option<PadSize>,
+                    station.body.as_ref().map(|body| body.id as i64),
                 )
                 .execute(pool)
                 .await;
@@ -110,8 +357,8 @@ async fn read_stations(
                     }
                     Err(error) => {
                         // only show warnings if they aren't duplicates and aren't caused by player
-                        // carriers (most player carriers are not in the systems list, and we don't
-                        // need them for the trade planner)
+                        // carriers (most player carriers are not in the systems list, and their
+                        // positions here are stale anyway: the EDDN listener tracks them instead)
                         // if !error.to_string().contains("duplicate")
                         if !station
                                 .typ
@@ -133,20 +380,95 @@ async fn read_stations(
     Ok(())
 }
 
+/// Ingests EDSM's dumps, inserting `batch_size` rows per transaction. Any of the dumps can be left out. The full
+/// systems dump (systemsWithCoordinates.json) is imported first, so that systems also in the populated dump end up
+/// flagged as populated.
 pub async fn ingest_edsm(
-    systems_json_path: std::path::PathBuf,
-    stations_json_path: std::path::PathBuf,
-    url: String
+    systems_json_path: Option<std::path::PathBuf>,
+    stations_json_path: Option<std::path::PathBuf>,
+    systems_with_coordinates_json_path: Option<std::path::PathBuf>,
+    batch_size: usize,
+    pool: &Pool<Postgres>,
 ) -> Result<()> {
-    info!("Setting up PostgreSQL pool");
-    let var_name = PgPoolOptions::new();
-    let pool = var_name
-        .max_connections(64)
-        .connect(&url)
-        .await?;
+    if let Some(path) = systems_with_coordinates_json_path {
+        read_systems_with_coordinates(path, pool).await?;
+    }
+    if let Some(path) = systems_json_path {
+        read_systems(path, batch_size, pool).await?;
+    }
+    if let Some(path) = stations_json_path {
+        read_stations(path, batch_size, pool).await?;
+    }
+
+    Ok(())
+}
 
-    read_systems(systems_json_path, &pool).await?;
-    read_stations(stations_json_path, &pool).await?;
+/// Streams EDSM's bodies dump (hundreds of millions of bodies) into the database. Bodies are updated if the dump
+/// has a newer version of them. Stations are linked to their body by `ingest-edsm` through EDSM's body id.
+pub async fn ingest_edsm_bodies(
+    bodies_json_path: std::path::PathBuf,
+    pool: &Pool<Postgres>,
+) -> Result<()> {
+    info!("Streaming bodies JSON");
+    let load = BulkLoad {
+        create: r#"
+            CREATE TEMPORARY TABLE IF NOT EXISTS bodies_import (
+                id BIGINT, id64 BIGINT, name TEXT, system_id BIGINT, system_id64 BIGINT, body_type TEXT,
+                sub_type TEXT, gravity FLOAT8, distance_to_arrival FLOAT8, landable BOOLEAN, updated_at TIMESTAMP
+            ) ON COMMIT DELETE ROWS;
+        "#,
+        copy: r#"
+            COPY bodies_import (
+                id, id64, name, system_id, system_id64, body_type, sub_type, gravity, distance_to_arrival,
+                landable, updated_at
+            ) FROM STDIN;
+        "#,
+        upsert: r#"
+            INSERT INTO bodies (
+                id, id64, name, system_id, system_id64, body_type, sub_type, gravity, distance_to_arrival,
+                landable, updated_at
+            )
+            SELECT * FROM bodies_import
+            ON CONFLICT (id) DO UPDATE SET
+                id64 = EXCLUDED.id64,
+                name = EXCLUDED.name,
+                system_id = EXCLUDED.system_id,
+                system_id64 = EXCLUDED.system_id64,
+                body_type = EXCLUDED.body_type,
+                sub_type = EXCLUDED.sub_type,
+                gravity = EXCLUDED.gravity,
+                distance_to_arrival = EXCLUDED.distance_to_arrival,
+                landable = EXCLUDED.landable,
+                updated_at = EXCLUDED.updated_at
+            WHERE bodies.updated_at < EXCLUDED.updated_at;
+        "#,
+    };
+    let (total, upserted, skipped) = stream_dump(
+        bodies_json_path,
+        &load,
+        |body: Body| {
+            format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                body.id,
+                copy_nullable(body.id64),
+                copy_escape(&body.name),
+                copy_nullable(body.system_id),
+                copy_nullable(body.system_id64),
+                copy_nullable(body.typ),
+                copy_nullable(body.sub_type),
+                copy_nullable(body.gravity),
+                copy_nullable(body.distance_to_arrival),
+                copy_nullable(body.is_landable),
+                body.update_time.naive_utc()
+            )
+        },
+        pool,
+    )
+    .await?;
 
+    info!(
+        "Read {} bodies, inserted or updated {} of them, skipped {} unparseable lines",
+        total, upserted, skipped
+    );
     Ok(())
 }
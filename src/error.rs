@@ -0,0 +1,60 @@
+// The error type returned by EDTear's library API, including the CLI commands. Only the binary uses color_eyre, so
+// library users (e.g. Kural) get a plain enum they can match on.
+
+/// Errors returned by EDTear's library functions
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("migration error: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// A listing from an EDDN market update couldn't be inserted
+    #[error("failed to insert commodity {commodity} for station {station} in {system}: {source}")]
+    Listing {
+        commodity: String,
+        station: String,
+        system: String,
+        source: sqlx::Error,
+    },
+
+    /// The database was migrated by a newer version of EDTear
+    #[error(
+        "database schema is at version {found}, but this version of EDTear only knows up to version {known}, please upgrade EDTear"
+    )]
+    SchemaTooNew { found: i64, known: i64 },
+
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+
+    /// The config file or an environment variable override is invalid
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    /// A command was given arguments it can't work with, e.g. an empty date range
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    /// Something a command was asked about doesn't exist, e.g. an unknown system
+    #[error("{0}")]
+    NotFound(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
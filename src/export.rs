@@ -0,0 +1,594 @@
+// Exports EDTear's data to files, so that it can be used without a Postgres connection.
+//
+// `export_snapshot` writes a self-contained snapshot of the latest trade data, so that Kural can build its model
+// without re-running heavy SQL against `listings`. The snapshot is a directory containing three Parquet files (Snappy compressed):
+//
+// systems.parquet: one row per system containing at least one exported station
+//   id (int64), name (utf8), x (float64), y (float64), z (float64)
+//
+// stations.parquet: one row per station with market data
+//   id (int64), market_id (int64), system_id (int64), name (utf8),
+//   distance_to_arrival (float32, nullable, ls), max_pad_size (utf8, nullable, "S", "M" or "L"),
+//   pad_size_inferred (bool, whether max_pad_size was guessed from the station type),
+//   station_type (utf8, nullable), planetary (bool, nullable), services (utf8, nullable, comma separated)
+//
+// listings.parquet: the most recent market snapshot of each station, one row per commodity
+//   market_id (int64), name (utf8), buy_price (int32), sell_price (int32), stock (int32), demand (int32),
+//   listed_at (timestamp, microseconds, UTC)
+//
+// `export_listings` writes the full listings history over a time range for analysis in pandas/DuckDB, either as a
+// single CSV file or as Parquet partitioned by day (`date=YYYY-MM-DD/listings.parquet`), with the columns:
+//   market_id, station_id, station_name, system_id, system_name, x, y, z, commodity, mean_price, buy_price,
+//   sell_price, demand, demand_bracket, stock, stock_bracket, listed_at
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use arrow::{
+    array::{
+        ArrayRef, BooleanArray, Float32Array, Float64Array, Int32Array, Int64Array, RecordBatch,
+        StringArray, TimestampMicrosecondArray,
+    },
+    datatypes::{DataType, Field, Schema, TimeUnit},
+};
+use chrono::{NaiveDate, NaiveDateTime, TimeDelta, Utc};
+use indicatif::ProgressBar;
+use log::info;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use serde::Serialize;
+use sqlx::{postgres::PgPoolOptions, FromRow};
+
+use crate::{query::Sphere, Error, Result};
+
+/// Number of rows fetched from the server-side cursor at a time when exporting listings history
+const CURSOR_FETCH_SIZE: usize = 10_000;
+
+/// File format for listings history exports
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ListingsFormat {
+    /// Parquet, partitioned by day
+    Parquet,
+    /// A single CSV file
+    Csv,
+}
+
+fn parquet_writer(path: &Path, batch: &RecordBatch) -> Result<ArrowWriter<File>> {
+    let file = File::create(path)?;
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    Ok(ArrowWriter::try_new(file, batch.schema(), Some(props))?)
+}
+
+/// Writes a single record batch to a Parquet file
+fn write_parquet(path: &Path, batch: RecordBatch) -> Result<()> {
+    let mut writer = parquet_writer(path, &batch)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Builds a record batch from named columns, deriving the schema from the arrays
+fn batch(columns: Vec<(&str, ArrayRef, bool)>) -> Result<RecordBatch> {
+    let schema = Schema::new(
+        columns
+            .iter()
+            .map(|(name, array, nullable)| Field::new(*name, array.data_type().clone(), *nullable))
+            .collect::<Vec<_>>(),
+    );
+    let arrays = columns.into_iter().map(|(_, array, _)| array).collect();
+    Ok(RecordBatch::try_new(Arc::new(schema), arrays)?)
+}
+
+fn timestamps(values: Vec<NaiveDateTime>) -> ArrayRef {
+    Arc::new(
+        TimestampMicrosecondArray::from(
+            values
+                .iter()
+                .map(|time| time.and_utc().timestamp_micros())
+                .collect::<Vec<_>>(),
+        )
+        .with_data_type(DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))),
+    )
+}
+
+pub async fn export_snapshot(
+    out_dir: std::path::PathBuf,
+    max_age_hours: Option<i64>,
+    sphere: Option<Sphere>,
+    url: String,
+    pool_size: u32,
+) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    std::fs::create_dir_all(&out_dir)?;
+    let since = max_age_hours.map(|hours| (Utc::now() - TimeDelta::hours(hours)).naive_utc());
+    let (x, y, z, radius) = match sphere {
+        Some(sphere) => (
+            Some(sphere.x),
+            Some(sphere.y),
+            Some(sphere.z),
+            Some(sphere.radius),
+        ),
+        None => (None, None, None, None),
+    };
+
+    info!("Exporting latest listings");
+    let listings = sqlx::query!(
+        r#"
+        SELECT l.market_id, l.name, l.buy_price, l.sell_price, l.stock, l.demand, l.listed_at
+        FROM latest_listings l
+        INNER JOIN stations stat ON stat.market_id = l.market_id
+        INNER JOIN systems sys ON sys.id = stat.system_id
+        WHERE ($1::timestamp IS NULL OR l.listed_at >= $1)
+        AND ($2::float8 IS NULL
+            OR ST_3DDWithin(sys.coords, ST_MakePoint($2, $3, $4), $5))
+        ORDER BY l.market_id, l.name;
+    "#,
+        since,
+        x,
+        y,
+        z,
+        radius
+    )
+    .fetch_all(&pool)
+    .await?;
+    info!("Exporting {} listings", listings.len());
+
+    let mut market_ids: Vec<i64> = listings.iter().map(|l| l.market_id).collect();
+    market_ids.dedup();
+
+    write_parquet(
+        &out_dir.join("listings.parquet"),
+        batch(vec![
+            (
+                "market_id",
+                Arc::new(Int64Array::from_iter_values(listings.iter().map(|l| l.market_id))),
+                false,
+            ),
+            (
+                "name",
+                Arc::new(StringArray::from_iter_values(listings.iter().map(|l| &l.name))),
+                false,
+            ),
+            (
+                "buy_price",
+                Arc::new(Int32Array::from_iter_values(listings.iter().map(|l| l.buy_price))),
+                false,
+            ),
+            (
+                "sell_price",
+                Arc::new(Int32Array::from_iter_values(listings.iter().map(|l| l.sell_price))),
+                false,
+            ),
+            (
+                "stock",
+                Arc::new(Int32Array::from_iter_values(listings.iter().map(|l| l.stock))),
+                false,
+            ),
+            (
+                "demand",
+                Arc::new(Int32Array::from_iter_values(listings.iter().map(|l| l.demand))),
+                false,
+            ),
+            (
+                "listed_at",
+                timestamps(listings.iter().map(|l| l.listed_at).collect()),
+                false,
+            ),
+        ])?,
+    )?;
+
+    info!("Exporting stations");
+    let stations = sqlx::query!(
+        r#"
+        SELECT id, market_id AS "market_id!", system_id AS "system_id!", name, distance_to_arrival,
+            max_pad_size::text AS max_pad_size, pad_size_inferred AS "pad_size_inferred!",
+            station_type, planetary, array_to_string(services, ',') AS services
+        FROM stations
+        WHERE market_id = ANY($1)
+        ORDER BY market_id;
+    "#,
+        &market_ids
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut system_ids: Vec<i64> = stations.iter().map(|s| s.system_id).collect();
+    system_ids.sort_unstable();
+    system_ids.dedup();
+
+    write_parquet(
+        &out_dir.join("stations.parquet"),
+        batch(vec![
+            (
+                "id",
+                Arc::new(Int64Array::from_iter_values(stations.iter().map(|s| s.id))),
+                false,
+            ),
+            (
+                "market_id",
+                Arc::new(Int64Array::from_iter_values(stations.iter().map(|s| s.market_id))),
+                false,
+            ),
+            (
+                "system_id",
+                Arc::new(Int64Array::from_iter_values(stations.iter().map(|s| s.system_id))),
+                false,
+            ),
+            (
+                "name",
+                Arc::new(StringArray::from_iter_values(stations.iter().map(|s| &s.name))),
+                false,
+            ),
+            (
+                "distance_to_arrival",
+                Arc::new(Float32Array::from_iter(
+                    stations.iter().map(|s| s.distance_to_arrival),
+                )),
+                true,
+            ),
+            (
+                "max_pad_size",
+                Arc::new(StringArray::from_iter(
+                    stations.iter().map(|s| s.max_pad_size.as_deref()),
+                )),
+                true,
+            ),
+            (
+                "pad_size_inferred",
+                Arc::new(BooleanArray::from_iter(
+                    stations.iter().map(|s| Some(s.pad_size_inferred)),
+                )),
+                false,
+            ),
+            (
+                "station_type",
+                Arc::new(StringArray::from_iter(
+                    stations.iter().map(|s| s.station_type.as_deref()),
+                )),
+                true,
+            ),
+            (
+                "planetary",
+                Arc::new(BooleanArray::from_iter(
+                    stations.iter().map(|s| s.planetary),
+                )),
+                true,
+            ),
+            (
+                "services",
+                Arc::new(StringArray::from_iter(
+                    stations.iter().map(|s| s.services.as_deref()),
+                )),
+                true,
+            ),
+        ])?,
+    )?;
+
+    info!("Exporting systems");
+    let systems = sqlx::query!(
+        r#"
+        SELECT id, name, ST_X(coords) AS "x!", ST_Y(coords) AS "y!", ST_Z(coords) AS "z!"
+        FROM systems
+        WHERE id = ANY($1)
+        ORDER BY id;
+    "#,
+        &system_ids
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    write_parquet(
+        &out_dir.join("systems.parquet"),
+        batch(vec![
+            (
+                "id",
+                Arc::new(Int64Array::from_iter_values(systems.iter().map(|s| s.id))),
+                false,
+            ),
+            (
+                "name",
+                Arc::new(StringArray::from_iter_values(systems.iter().map(|s| &s.name))),
+                false,
+            ),
+            (
+                "x",
+                Arc::new(Float64Array::from_iter_values(systems.iter().map(|s| s.x))),
+                false,
+            ),
+            (
+                "y",
+                Arc::new(Float64Array::from_iter_values(systems.iter().map(|s| s.y))),
+                false,
+            ),
+            (
+                "z",
+                Arc::new(Float64Array::from_iter_values(systems.iter().map(|s| s.z))),
+                false,
+            ),
+        ])?,
+    )?;
+
+    info!(
+        "Exported {} systems, {} stations and {} listings to {}",
+        systems.len(),
+        stations.len(),
+        listings.len(),
+        out_dir.display()
+    );
+
+    Ok(())
+}
+
+/// A row of listings history, joined with its station and system
+#[derive(FromRow, Serialize, Debug)]
+struct HistoryRow {
+    market_id: i64,
+    station_id: i64,
+    station_name: String,
+    system_id: Option<i64>,
+    system_name: Option<String>,
+    x: Option<f64>,
+    y: Option<f64>,
+    z: Option<f64>,
+    commodity: String,
+    mean_price: i32,
+    buy_price: i32,
+    sell_price: i32,
+    demand: i32,
+    demand_bracket: i32,
+    stock: i32,
+    stock_bracket: i32,
+    listed_at: NaiveDateTime,
+}
+
+fn history_batch(rows: &[HistoryRow]) -> Result<RecordBatch> {
+    batch(vec![
+        (
+            "market_id",
+            Arc::new(Int64Array::from_iter_values(
+                rows.iter().map(|r| r.market_id),
+            )),
+            false,
+        ),
+        (
+            "station_id",
+            Arc::new(Int64Array::from_iter_values(
+                rows.iter().map(|r| r.station_id),
+            )),
+            false,
+        ),
+        (
+            "station_name",
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|r| &r.station_name),
+            )),
+            false,
+        ),
+        (
+            "system_id",
+            Arc::new(Int64Array::from_iter(rows.iter().map(|r| r.system_id))),
+            true,
+        ),
+        (
+            "system_name",
+            Arc::new(StringArray::from_iter(
+                rows.iter().map(|r| r.system_name.as_deref()),
+            )),
+            true,
+        ),
+        (
+            "x",
+            Arc::new(Float64Array::from_iter(rows.iter().map(|r| r.x))),
+            true,
+        ),
+        (
+            "y",
+            Arc::new(Float64Array::from_iter(rows.iter().map(|r| r.y))),
+            true,
+        ),
+        (
+            "z",
+            Arc::new(Float64Array::from_iter(rows.iter().map(|r| r.z))),
+            true,
+        ),
+        (
+            "commodity",
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|r| &r.commodity),
+            )),
+            false,
+        ),
+        (
+            "mean_price",
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|r| r.mean_price),
+            )),
+            false,
+        ),
+        (
+            "buy_price",
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|r| r.buy_price),
+            )),
+            false,
+        ),
+        (
+            "sell_price",
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|r| r.sell_price),
+            )),
+            false,
+        ),
+        (
+            "demand",
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.demand))),
+            false,
+        ),
+        (
+            "demand_bracket",
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|r| r.demand_bracket),
+            )),
+            false,
+        ),
+        (
+            "stock",
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|r| r.stock))),
+            false,
+        ),
+        (
+            "stock_bracket",
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|r| r.stock_bracket),
+            )),
+            false,
+        ),
+        (
+            "listed_at",
+            timestamps(rows.iter().map(|r| r.listed_at).collect()),
+            false,
+        ),
+    ])
+}
+
+/// Destination for exported listings history
+enum HistorySink {
+    Csv(csv::Writer<File>),
+    Parquet {
+        out_dir: PathBuf,
+        /// The day currently being written, and the writer for its partition
+        current: Option<(NaiveDate, ArrowWriter<File>)>,
+    },
+}
+
+impl HistorySink {
+    fn write(&mut self, rows: &[HistoryRow]) -> Result<()> {
+        match self {
+            HistorySink::Csv(writer) => {
+                for row in rows {
+                    writer.serialize(row)?;
+                }
+            }
+            HistorySink::Parquet { out_dir, current } => {
+                // rows arrive ordered by listed_at, so each day is one contiguous run
+                for day_rows in rows.chunk_by(|a, b| a.listed_at.date() == b.listed_at.date()) {
+                    let day = day_rows[0].listed_at.date();
+                    let batch = history_batch(day_rows)?;
+
+                    if current
+                        .as_ref()
+                        .is_none_or(|(current_day, _)| *current_day != day)
+                    {
+                        if let Some((_, writer)) = current.take() {
+                            writer.close()?;
+                        }
+                        let partition = out_dir.join(format!("date={}", day.format("%Y-%m-%d")));
+                        std::fs::create_dir_all(&partition)?;
+                        let writer = parquet_writer(&partition.join("listings.parquet"), &batch)?;
+                        *current = Some((day, writer));
+                    }
+
+                    if let Some((_, writer)) = current {
+                        writer.write(&batch)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            HistorySink::Csv(mut writer) => writer.flush()?,
+            HistorySink::Parquet { current, .. } => {
+                if let Some((_, writer)) = current {
+                    writer.close()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn export_listings(
+    out_dir: std::path::PathBuf,
+    from: NaiveDate,
+    to: NaiveDate,
+    format: ListingsFormat,
+    url: String,
+    pool_size: u32,
+) -> Result<()> {
+    if from >= to {
+        return Err(Error::InvalidArgument(format!(
+            "Nothing to export: {} is not before {}",
+            from, to
+        )));
+    }
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    std::fs::create_dir_all(&out_dir)?;
+    let mut sink = match format {
+        ListingsFormat::Csv => {
+            HistorySink::Csv(csv::Writer::from_path(out_dir.join("listings.csv"))?)
+        }
+        ListingsFormat::Parquet => HistorySink::Parquet {
+            out_dir: out_dir.clone(),
+            current: None,
+        },
+    };
+
+    // a server-side cursor lets us stream the table without holding it all in memory on either end. cursors
+    // only live as long as their transaction.
+    let mut transaction = pool.begin().await?;
+    sqlx::query(
+        r#"
+        DECLARE listings_export NO SCROLL CURSOR FOR
+        SELECT l.market_id, stat.id AS station_id, stat.name AS station_name, sys.id AS system_id,
+            sys.name AS system_name, ST_X(sys.coords) AS x, ST_Y(sys.coords) AS y, ST_Z(sys.coords) AS z,
+            l.name AS commodity, l.mean_price, l.buy_price, l.sell_price, l.demand, l.demand_bracket,
+            l.stock, l.stock_bracket, l.listed_at
+        FROM listings l
+        INNER JOIN stations stat ON stat.market_id = l.market_id
+        LEFT JOIN systems sys ON sys.id = stat.system_id
+        WHERE l.listed_at >= $1 AND l.listed_at < $2
+        ORDER BY l.listed_at;
+    "#,
+    )
+    .bind(from.and_hms_opt(0, 0, 0))
+    .bind(to.and_hms_opt(0, 0, 0))
+    .execute(&mut *transaction)
+    .await?;
+
+    info!("Exporting listings from {} until {}", from, to);
+    let progress = ProgressBar::new_spinner();
+    let mut total = 0;
+    loop {
+        let rows: Vec<HistoryRow> =
+            sqlx::query_as(&format!("FETCH {CURSOR_FETCH_SIZE} FROM listings_export;"))
+                .fetch_all(&mut *transaction)
+                .await?;
+        if rows.is_empty() {
+            break;
+        }
+        sink.write(&rows)?;
+        total += rows.len();
+        progress.set_message(format!("{} listings", total));
+        progress.tick();
+    }
+    progress.finish_and_clear();
+
+    transaction.rollback().await?;
+    sink.finish()?;
+
+    info!("Exported {} listings to {}", total, out_dir.display());
+    Ok(())
+}
@@ -0,0 +1,180 @@
+// Tracks the minor factions and powerplay state of populated systems. Prices depend heavily on system states
+// (e.g. Boom, Expansion or Famine) and on powerplay control, so they're seeded from EDSM's populated systems dump
+// and kept up to date from commanders' FSDJump and Location events. Whenever a system's state changes, a row is
+// added to the history tables so that state changes can be correlated with price moves.
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+use crate::Error;
+
+/// A minor faction's standing in a system
+#[derive(Serialize, Debug, Clone)]
+pub struct FactionPresence {
+    pub faction: String,
+    /// Share of the system's influence, from 0 to 1
+    pub influence: f32,
+    /// The faction's main state, if it's in one
+    pub state: Option<String>,
+    pub active_states: Vec<String>,
+    pub pending_states: Vec<String>,
+    pub recovering_states: Vec<String>,
+    pub allegiance: Option<String>,
+    pub government: Option<String>,
+    pub happiness: Option<String>,
+}
+
+/// Powerplay state of a system
+#[derive(Debug, Clone, Default)]
+pub struct Powerplay {
+    pub controlling_power: Option<String>,
+    pub powers: Vec<String>,
+    pub powerplay_state: Option<String>,
+}
+
+/// The factions and powerplay state of a system, as seen at a point in time
+#[derive(Debug, Clone)]
+pub struct SystemFactions {
+    pub system_id64: i64,
+    pub system_name: String,
+    pub controlling_faction: Option<String>,
+    /// None if the source doesn't say (EDSM's dump has no powerplay), in which case we keep what we know
+    pub powerplay: Option<Powerplay>,
+    pub factions: Vec<FactionPresence>,
+    pub seen_at: NaiveDateTime,
+}
+
+/// Journal and EDSM states use "None" when a faction isn't in a state
+pub fn state_name(state: Option<&str>) -> Option<String> {
+    state.filter(|state| *state != "None").map(str::to_string)
+}
+
+/// Updates a system's factions and powerplay state, unless we already know its state at a later time. Factions no
+/// longer present are removed, and anything that changed (including a faction retreating, as a row without
+/// influence) is added to the history. Returns whether the system was updated.
+pub async fn update_system_factions(
+    system: &SystemFactions,
+    pool: &Pool<Postgres>,
+) -> Result<bool, Error> {
+    let mut transaction = pool.begin().await?;
+    let updated_at = sqlx::query_scalar!(
+        "SELECT updated_at FROM system_states WHERE system_id64 = $1 FOR UPDATE;",
+        system.system_id64
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if updated_at.is_some_and(|updated_at| updated_at >= system.seen_at) {
+        return Ok(false);
+    }
+
+    let powerplay = system.powerplay.as_ref();
+    sqlx::query!(
+        r#"
+        WITH previous AS (
+            SELECT * FROM system_states WHERE system_id64 = $1
+        ),
+        incoming AS (
+            SELECT $1::bigint AS system_id64, $2::text AS controlling_faction,
+                CASE WHEN $3 THEN $4::text ELSE (SELECT controlling_power FROM previous) END AS controlling_power,
+                CASE WHEN $3 THEN $5::text[] ELSE (SELECT powers FROM previous) END AS powers,
+                CASE WHEN $3 THEN $6::text ELSE (SELECT powerplay_state FROM previous) END AS powerplay_state
+        ),
+        history AS (
+            INSERT INTO system_state_history (
+                system_id64, controlling_faction, controlling_power, powers, powerplay_state, seen_at
+            )
+            SELECT i.*, $7 FROM incoming i
+            WHERE NOT EXISTS (
+                SELECT 1 FROM previous p
+                WHERE (p.controlling_faction, p.controlling_power, p.powers, p.powerplay_state)
+                    IS NOT DISTINCT FROM (i.controlling_faction, i.controlling_power, i.powers, i.powerplay_state)
+            )
+            ON CONFLICT DO NOTHING
+        )
+        INSERT INTO system_states (
+            system_id64, controlling_faction, controlling_power, powers, powerplay_state, updated_at
+        )
+        SELECT i.*, $7 FROM incoming i
+        ON CONFLICT (system_id64) DO UPDATE SET
+            controlling_faction = EXCLUDED.controlling_faction,
+            controlling_power = EXCLUDED.controlling_power,
+            powers = EXCLUDED.powers,
+            powerplay_state = EXCLUDED.powerplay_state,
+            updated_at = EXCLUDED.updated_at;
+    "#,
+        system.system_id64,
+        system.controlling_faction,
+        powerplay.is_some(),
+        powerplay.and_then(|powerplay| powerplay.controlling_power.as_deref()),
+        powerplay.map(|powerplay| powerplay.powers.as_slice()),
+        powerplay.and_then(|powerplay| powerplay.powerplay_state.as_deref()),
+        system.seen_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        WITH incoming AS (
+            SELECT * FROM jsonb_to_recordset($2) AS f(
+                faction TEXT, influence REAL, state TEXT, active_states TEXT[], pending_states TEXT[],
+                recovering_states TEXT[], allegiance TEXT, government TEXT, happiness TEXT
+            )
+        ),
+        history AS (
+            INSERT INTO faction_presence_history (
+                system_id64, faction, influence, state, active_states, pending_states, recovering_states, seen_at
+            )
+            SELECT $1, i.faction, i.influence, i.state, i.active_states, i.pending_states, i.recovering_states, $3
+            FROM incoming i
+            LEFT JOIN faction_presences p ON p.system_id64 = $1 AND p.faction = i.faction
+            WHERE (p.influence, p.state, p.active_states, p.pending_states, p.recovering_states)
+                IS DISTINCT FROM (i.influence, i.state, i.active_states, i.pending_states, i.recovering_states)
+            ON CONFLICT DO NOTHING
+        )
+        INSERT INTO faction_presences (
+            system_id64, faction, influence, state, active_states, pending_states, recovering_states, allegiance,
+            government, happiness, updated_at
+        )
+        SELECT $1, i.*, $3 FROM incoming i
+        ON CONFLICT (system_id64, faction) DO UPDATE SET
+            influence = EXCLUDED.influence,
+            state = EXCLUDED.state,
+            active_states = EXCLUDED.active_states,
+            pending_states = EXCLUDED.pending_states,
+            recovering_states = EXCLUDED.recovering_states,
+            allegiance = EXCLUDED.allegiance,
+            government = EXCLUDED.government,
+            happiness = EXCLUDED.happiness,
+            updated_at = EXCLUDED.updated_at;
+    "#,
+        system.system_id64,
+        serde_json::to_value(&system.factions)?,
+        system.seen_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    // factions that have retreated from the system, recorded in the history without influence or states
+    sqlx::query!(
+        r#"
+        WITH retreated AS (
+            DELETE FROM faction_presences WHERE system_id64 = $1 AND updated_at < $2
+            RETURNING faction
+        )
+        INSERT INTO faction_presence_history (
+            system_id64, faction, influence, state, active_states, pending_states, recovering_states, seen_at
+        )
+        SELECT $1, faction, NULL, NULL, '{}', '{}', '{}', $2 FROM retreated
+        ON CONFLICT DO NOTHING;
+    "#,
+        system.system_id64,
+        system.seen_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+    Ok(true)
+}
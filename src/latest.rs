@@ -0,0 +1,61 @@
+// Maintains the `latest_listings` table, which holds the latest snapshot of each market so that "current
+// prices" queries are O(markets) rather than O(history). The EDDN listener upserts into it as listings arrive;
+// `rebuild_latest_listings` recomputes it from the full listings history.
+
+use chrono::NaiveDateTime;
+use log::info;
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+
+use crate::{Error, Result};
+
+/// Removes commodities that a market no longer lists, i.e. that weren't part of its snapshot at `listed_at`
+pub async fn prune_market(
+    market_id: i64,
+    listed_at: NaiveDateTime,
+    pool: &Pool<Postgres>,
+) -> Result<u64, Error> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM latest_listings
+        WHERE market_id = $1
+        AND listed_at < $2;
+    "#,
+        market_id,
+        listed_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Recomputes the `latest_listings` table from scratch, using the latest snapshot of every market in the
+/// listings history
+pub async fn rebuild_latest_listings(url: String) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(1).connect(&url).await?;
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query!("DELETE FROM latest_listings;")
+        .execute(&mut *transaction)
+        .await?;
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO latest_listings
+        SELECT l.market_id, l.name, l.mean_price, l.buy_price, l.sell_price, l.demand, l.demand_bracket,
+            l.stock, l.stock_bracket, l.listed_at
+        FROM listings l
+        INNER JOIN (
+            SELECT market_id, MAX(listed_at) AS listed_at
+            FROM listings
+            GROUP BY market_id
+        ) latest ON l.market_id = latest.market_id AND l.listed_at = latest.listed_at;
+    "#
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    info!("Rebuilt latest listings: {} rows", result.rows_affected());
+    Ok(())
+}
@@ -0,0 +1,31 @@
+#![feature(duration_constructors_lite)]
+
+//! EDTear, the Elite: Dangerous Trade Ear, as a library.
+//!
+//! Functions that take a `Pool<Postgres>` are the library API and return [`Error`]: the EDSM and Spansh
+//! ingesters ([`edsm::ingest_edsm`], [`spansh::read_spansh_stations`]), the EDDN message handler
+//! ([`eddn::handle_envelope`]), the typed queries in [`query`] and [`stats::compute_stats`], and the router
+//! ([`route::plot_route`]). Functions that take a connection URL are the CLI's commands; they
+//! return [`Error`] too, and only the binary wraps errors with `color_eyre`.
+
+pub mod alerts;
+pub mod anomaly;
+pub mod api;
+pub mod carriers;
+pub mod config;
+pub mod eddn;
+pub mod edsm;
+pub mod error;
+pub mod export;
+pub mod factions;
+pub mod latest;
+pub mod maintenance;
+pub mod migrate;
+pub mod notify;
+pub mod query;
+pub mod route;
+pub mod spansh;
+pub mod stats;
+pub mod stream;
+
+pub use error::{Error, Result};
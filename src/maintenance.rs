@@ -0,0 +1,252 @@
+// Maintains the monthly partitions of the listings table. Partitions are created a few months ahead of time so
+// that inserts never fail, and the retention policy downsamples months older than the retention period to the
+// last snapshot of each market per day, replacing (and dropping) the original partition. Months older than the
+// drop horizon are dropped outright.
+
+use chrono::{Datelike, Days, NaiveDate, Utc};
+use log::info;
+use sqlx::{postgres::PgPoolOptions, PgExecutor, Pool, Postgres};
+
+use crate::{Error, Result};
+
+/// Number of months ahead to create listings partitions for, by default
+pub const MONTHS_AHEAD: u32 = 3;
+
+/// Name of the listings partition for the given month, e.g. listings_2025_01
+fn partition_name(month: NaiveDate) -> String {
+    month.format("listings_%Y_%m").to_string()
+}
+
+/// Returns the first month that isn't entirely older than `days` days before `today`. Partitions for months
+/// before it only hold listings older than that.
+fn cutoff_month(today: NaiveDate, days: u32) -> NaiveDate {
+    let cutoff = today - Days::new(days as u64);
+    cutoff.with_day(1).unwrap()
+}
+
+/// Creates the listings partitions for this month and the next `months_ahead` months, if they don't already
+/// exist. Returns the number of partitions created.
+pub async fn create_partitions(months_ahead: u32, pool: &Pool<Postgres>) -> Result<usize, Error> {
+    let created = sqlx::query!(
+        r#"
+        SELECT create_listings_partition(month::date) AS "created!"
+        FROM generate_series(
+            date_trunc('month', NOW()),
+            date_trunc('month', NOW()) + make_interval(months => $1),
+            INTERVAL '1 month'
+        ) AS month;
+    "#,
+        months_ahead as i32
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(created.iter().filter(|row| row.created).count())
+}
+
+/// Creates the listings partition for the month containing `day`, e.g. to import historical listings. Returns
+/// whether it was created, i.e. false if it already exists. Takes any executor, so that the partition can be
+/// created in the same transaction as the listings that need it.
+pub async fn create_partition(
+    day: NaiveDate,
+    executor: impl PgExecutor<'_>,
+) -> Result<bool, Error> {
+    let created = sqlx::query_scalar!(
+        r#"SELECT create_listings_partition($1) AS "created!";"#,
+        day
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(created)
+}
+
+/// Downsamples a month of listings to the last snapshot of each market per day. The partition is detached and
+/// replaced by a new one holding only the downsampled listings, then dropped.
+async fn downsample_partition(month: NaiveDate, pool: &Pool<Postgres>) -> Result<(), Error> {
+    let partition = partition_name(month);
+    let original = format!("{partition}_original");
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query(&format!(
+        "ALTER TABLE listings DETACH PARTITION {partition};"
+    ))
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(&format!("ALTER TABLE {partition} RENAME TO {original};"))
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query!(
+        r#"SELECT create_listings_partition($1) AS "created!";"#,
+        month
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {original};"))
+        .fetch_one(&mut *transaction)
+        .await?;
+    let kept = sqlx::query(&format!(
+        r#"
+        INSERT INTO listings
+        SELECT l.*
+        FROM {original} l
+        INNER JOIN (
+            SELECT market_id, MAX(listed_at) AS listed_at
+            FROM {original}
+            GROUP BY market_id, listed_at::date
+        ) last ON l.market_id = last.market_id AND l.listed_at = last.listed_at;
+    "#
+    ))
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    sqlx::query(&format!("DROP TABLE {original};"))
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query!(
+        "UPDATE listings_partitions SET downsampled = TRUE WHERE month = $1;",
+        month
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    info!(
+        "Downsampled {}: kept {} of {} listings",
+        partition, kept, total
+    );
+    Ok(())
+}
+
+/// Downsamples every month that ended more than `retention_days` days ago and hasn't been downsampled yet
+pub async fn apply_retention(retention_days: u32, pool: &Pool<Postgres>) -> Result<(), Error> {
+    let months = sqlx::query!(
+        r#"
+        SELECT month
+        FROM listings_partitions
+        WHERE NOT downsampled
+        AND month < $1
+        ORDER BY month;
+    "#,
+        cutoff_month(Utc::now().date_naive(), retention_days)
+    )
+    .fetch_all(pool)
+    .await?;
+
+    if months.is_empty() {
+        info!(
+            "No listings older than {} days to downsample",
+            retention_days
+        );
+    }
+    for row in months {
+        downsample_partition(row.month, pool).await?;
+    }
+    Ok(())
+}
+
+/// Detaches and drops the partition for a month, forgetting its listings entirely
+async fn drop_partition(month: NaiveDate, pool: &Pool<Postgres>) -> Result<(), Error> {
+    let partition = partition_name(month);
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query(&format!(
+        "ALTER TABLE listings DETACH PARTITION {partition};"
+    ))
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(&format!("DROP TABLE {partition};"))
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query!("DELETE FROM listings_partitions WHERE month = $1;", month)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+
+    info!("Dropped {}", partition);
+    Ok(())
+}
+
+/// Drops every month that ended more than `drop_after_days` days ago. The latest listings are kept, so markets
+/// nobody has visited since still have current prices.
+pub async fn drop_expired(drop_after_days: u32, pool: &Pool<Postgres>) -> Result<(), Error> {
+    let months = sqlx::query!(
+        r#"
+        SELECT month
+        FROM listings_partitions
+        WHERE month < $1
+        ORDER BY month;
+    "#,
+        cutoff_month(Utc::now().date_naive(), drop_after_days)
+    )
+    .fetch_all(pool)
+    .await?;
+
+    if months.is_empty() {
+        info!("No listings older than {} days to drop", drop_after_days);
+    }
+    for row in months {
+        drop_partition(row.month, pool).await?;
+    }
+    Ok(())
+}
+
+pub async fn maintenance(
+    months_ahead: u32,
+    retention_days: Option<u32>,
+    drop_after_days: Option<u32>,
+    url: String,
+) -> Result<()> {
+    if let (Some(retention_days), Some(drop_after_days)) = (retention_days, drop_after_days) {
+        if drop_after_days < retention_days {
+            return Err(Error::InvalidArgument(format!(
+                "--drop-after-days ({}) can't be less than --retention-days ({})",
+                drop_after_days, retention_days
+            )));
+        }
+    }
+
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(1).connect(&url).await?;
+
+    let created = create_partitions(months_ahead, &pool).await?;
+    info!("Created {} new listings partitions", created);
+
+    // drop first, so that we don't bother downsampling months that are about to go
+    if let Some(drop_after_days) = drop_after_days {
+        drop_expired(drop_after_days, &pool).await?;
+    }
+    if let Some(retention_days) = retention_days {
+        apply_retention(retention_days, &pool).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn cutoff_is_the_month_containing_the_cutoff_day() {
+        assert_eq!(cutoff_month(date(2025, 3, 15), 0), date(2025, 3, 1));
+        assert_eq!(cutoff_month(date(2025, 3, 15), 14), date(2025, 3, 1));
+        assert_eq!(cutoff_month(date(2025, 3, 15), 15), date(2025, 2, 1));
+        assert_eq!(cutoff_month(date(2025, 3, 15), 90), date(2024, 12, 1));
+    }
+
+    #[test]
+    fn cutoff_handles_month_and_year_boundaries() {
+        // a month only expires once its last day is past the cutoff
+        assert_eq!(cutoff_month(date(2025, 3, 1), 0), date(2025, 3, 1));
+        assert_eq!(cutoff_month(date(2025, 3, 1), 1), date(2025, 2, 1));
+        assert_eq!(cutoff_month(date(2024, 3, 1), 1), date(2024, 2, 1));
+        assert_eq!(cutoff_month(date(2025, 1, 1), 1), date(2024, 12, 1));
+        assert_eq!(cutoff_month(date(2025, 1, 1), 365), date(2024, 1, 1));
+        assert_eq!(cutoff_month(date(2025, 1, 1), 367), date(2023, 12, 1));
+    }
+}
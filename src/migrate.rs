@@ -0,0 +1,88 @@
+// Embeds the migrations in migrations/ into the binary, so that the database can be set up and upgraded without
+// sqlx-cli (e.g. in Docker).
+
+use log::{info, warn};
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    postgres::PgPoolOptions,
+    Pool, Postgres,
+};
+
+use crate::{Error, Result};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Checks the database schema against the migrations this binary knows about. Fails if the database has been
+/// migrated by a newer version of EDTear, otherwise returns the number of pending migrations. This is read-only:
+/// a database without the migrations table counts as having none applied.
+pub async fn check_schema(pool: &Pool<Postgres>) -> Result<usize, Error> {
+    let mut conn = pool.acquire().await?;
+    let has_table = sqlx::query_scalar!(
+        r#"SELECT to_regclass('_sqlx_migrations') IS NOT NULL AS "exists!";"#
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let applied = if has_table {
+        conn.list_applied_migrations().await?
+    } else {
+        Vec::new()
+    };
+
+    let known = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+    if let Some(newest) = applied.iter().map(|m| m.version).max() {
+        if newest > known {
+            return Err(Error::SchemaTooNew {
+                found: newest,
+                known,
+            });
+        }
+    }
+
+    let pending = MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .filter(|m| !applied.iter().any(|a| a.version == m.version))
+        .count();
+    Ok(pending)
+}
+
+/// Applies any pending migrations
+pub async fn migrate(pool: &Pool<Postgres>) -> Result<(), Error> {
+    let pending = check_schema(pool).await?;
+    if pending == 0 {
+        info!("Database schema is up to date");
+        return Ok(());
+    }
+    info!("Applying {} migrations", pending);
+    MIGRATOR.run(pool).await?;
+    info!("Database schema is up to date");
+    Ok(())
+}
+
+/// Makes sure the schema is usable before running a command against the database: refuses to run against a
+/// newer schema, and applies pending migrations if `auto_migrate` is set (otherwise just warns about them)
+pub async fn ensure_schema(url: &str, auto_migrate: bool) -> Result<()> {
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(1).connect(url).await?;
+
+    if auto_migrate {
+        migrate(&pool).await?;
+        return Ok(());
+    }
+    let pending = check_schema(&pool).await?;
+    if pending > 0 {
+        warn!(
+            "Database has {} pending migrations. Run `edtear migrate` or pass --auto-migrate to apply them.",
+            pending
+        );
+    }
+    Ok(())
+}
+
+pub async fn run_migrations(url: String) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(1).connect(&url).await?;
+    migrate(&pool).await?;
+    Ok(())
+}
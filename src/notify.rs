@@ -0,0 +1,69 @@
+// Publishes market updates over Postgres LISTEN/NOTIFY, so that downstream services (including Kural) can
+// react to new data without polling.
+
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, Pool, Postgres};
+
+use crate::{Error, Result};
+
+/// Channel that market updates are published on
+pub const MARKET_UPDATED_CHANNEL: &str = "market_updated";
+
+/// JSON payload sent on the `market_updated` channel
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MarketNotification {
+    pub market_id: i64,
+    pub system: String,
+    pub station: String,
+    pub timestamp: NaiveDateTime,
+    pub commodity_count: usize,
+}
+
+/// Notifies listeners on the `market_updated` channel that a market has been updated
+pub async fn notify_market_updated(
+    notification: &MarketNotification,
+    pool: &Pool<Postgres>,
+) -> Result<(), Error> {
+    let payload = serde_json::to_string(notification)?;
+    sqlx::query!(
+        "SELECT pg_notify($1, $2);",
+        MARKET_UPDATED_CHANNEL,
+        payload
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Listens on the `market_updated` channel and prints each update as it arrives
+pub async fn watch(url: String) -> Result<()> {
+    info!("Connecting to PostgreSQL on {}", url);
+    let mut listener = PgListener::connect(&url).await?;
+    listener.listen(MARKET_UPDATED_CHANNEL).await?;
+    info!("Watching for market updates");
+
+    loop {
+        let notification = listener.recv().await?;
+        match serde_json::from_str::<MarketNotification>(notification.payload()) {
+            Ok(update) => {
+                println!(
+                    "[{}] {} in {} (market {}): {} commodities",
+                    update.timestamp,
+                    update.station,
+                    update.system,
+                    update.market_id,
+                    update.commodity_count
+                );
+            }
+            Err(error) => {
+                warn!(
+                    "Failed to parse notification {}: {}",
+                    notification.payload(),
+                    error
+                );
+            }
+        }
+    }
+}
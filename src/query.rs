@@ -0,0 +1,516 @@
+// Typed queries over the EDTear database, shared by the HTTP API and library users such as Kural.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use utoipa::ToSchema;
+
+use crate::Result;
+
+/// A sphere in galactic coordinates, in light years
+#[derive(Debug, Clone, Copy)]
+pub struct Sphere {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub radius: f64,
+}
+
+/// Size of a landing pad. Ordered from smallest to largest, so that a ship needing a medium pad can use any
+/// station with `max_pad_size >= PadSize::Medium`.
+#[derive(
+    sqlx::Type,
+    Serialize,
+    Deserialize,
+    ToSchema,
+    clap::ValueEnum,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[sqlx(type_name = "pad_size")]
+pub enum PadSize {
+    #[sqlx(rename = "S")]
+    #[serde(rename = "S")]
+    #[value(name = "S")]
+    Small,
+    #[sqlx(rename = "M")]
+    #[serde(rename = "M")]
+    #[value(name = "M")]
+    Medium,
+    #[sqlx(rename = "L")]
+    #[serde(rename = "L")]
+    #[value(name = "L")]
+    Large,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct System {
+    pub id: i64,
+    pub name: String,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    /// Whether the system is in EDSM's populated systems dump, rather than only the full dump
+    pub populated: bool,
+    /// Distance in light years from the queried point, if this was a proximity search
+    pub distance: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Station {
+    pub id: i64,
+    pub name: String,
+    pub market_id: Option<i64>,
+    pub distance_to_arrival: Option<f32>,
+    /// Number of landing pads of each size
+    pub pads_small: Option<i16>,
+    pub pads_medium: Option<i16>,
+    pub pads_large: Option<i16>,
+    /// Largest landing pad at the station
+    pub max_pad_size: Option<PadSize>,
+    /// Whether `max_pad_size` was guessed from the station type, because we don't have pad counts
+    pub pad_size_inferred: bool,
+    /// Station type as named by Spansh, e.g. "Coriolis Starport" or "Planetary Outpost"
+    pub station_type: Option<String>,
+    /// Whether the station is on a planet's surface
+    pub planetary: Option<bool>,
+    /// Services offered by the station, as named by Spansh, e.g. "Refuel" or "Market"
+    pub services: Option<Vec<String>>,
+    pub primary_economy: Option<String>,
+    pub government: Option<String>,
+    pub allegiance: Option<String>,
+    pub controlling_faction: Option<String>,
+    /// Body the station is on or orbiting, if the bodies dump has been imported
+    pub body_name: Option<String>,
+    /// Surface gravity of the body in g, which matters for planetary stations and settlements
+    pub body_gravity: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Listing {
+    pub name: String,
+    pub mean_price: i32,
+    pub buy_price: i32,
+    pub sell_price: i32,
+    pub demand: i32,
+    pub demand_bracket: i32,
+    pub stock: i32,
+    pub stock_bracket: i32,
+    pub listed_at: NaiveDateTime,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct PricePoint {
+    pub listed_at: NaiveDateTime,
+    pub mean_price: i32,
+    pub buy_price: i32,
+    pub sell_price: i32,
+    pub demand: i32,
+    pub stock: i32,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Offer {
+    pub market_id: i64,
+    pub station_name: String,
+    /// For fleet carriers, the system the carrier was last seen in
+    pub system_name: String,
+    /// Whether the station is a fleet carrier
+    pub carrier: bool,
+    pub max_pad_size: Option<PadSize>,
+    pub pad_size_inferred: bool,
+    pub distance_to_arrival: Option<f32>,
+    pub planetary: Option<bool>,
+    /// Surface gravity in g of the body the station is on, if known
+    pub body_gravity: Option<f64>,
+    /// Coordinates of the system
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    /// Distance in light years from the queried point
+    pub distance: f64,
+    /// Estimated jumps from the queried point, if a jump range was given
+    pub jumps: Option<i32>,
+    pub buy_price: i32,
+    pub sell_price: i32,
+    pub stock: i32,
+    pub demand: i32,
+    pub listed_at: NaiveDateTime,
+}
+
+impl Offer {
+    pub fn coords(&self) -> [f64; 3] {
+        [self.x, self.y, self.z]
+    }
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Carrier {
+    pub market_id: i64,
+    pub callsign: Option<String>,
+    pub name: Option<String>,
+    /// Where the carrier was last seen, if we know
+    pub system_id64: Option<i64>,
+    pub system_name: Option<String>,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+    pub located_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct CarrierPosition {
+    pub system_id64: i64,
+    pub system_name: String,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+    /// When the carrier was first seen in this system
+    pub seen_at: NaiveDateTime,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct SystemFaction {
+    pub faction: String,
+    /// Share of the system's influence, from 0 to 1
+    pub influence: Option<f32>,
+    /// Main state, e.g. Boom, Expansion or Famine
+    pub state: Option<String>,
+    pub active_states: Vec<String>,
+    pub pending_states: Vec<String>,
+    pub recovering_states: Vec<String>,
+    pub allegiance: Option<String>,
+    pub government: Option<String>,
+    pub happiness: Option<String>,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A faction's influence and states in a system from when they changed
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct FactionHistoryPoint {
+    pub faction: String,
+    /// None once the faction has retreated from the system
+    pub influence: Option<f32>,
+    pub state: Option<String>,
+    pub active_states: Vec<String>,
+    pub pending_states: Vec<String>,
+    pub recovering_states: Vec<String>,
+    pub seen_at: NaiveDateTime,
+}
+
+/// Which side of the trade to look for
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TradeKind {
+    /// Cheapest stations to buy from (stock > 0)
+    Buy,
+    /// Highest paying stations to sell to (demand > 0)
+    Sell,
+}
+
+/// Restricts which stations are considered by a query. The default matches every station.
+#[derive(Debug, Clone, Default)]
+pub struct StationFilter {
+    /// Only stations offering this service, e.g. "Refuel"
+    pub service: Option<String>,
+    /// Only planetary (true) or orbital (false) stations. Stations of unknown type never match.
+    pub planetary: Option<bool>,
+    /// Only stations with a landing pad of at least this size, including sizes inferred from the station type.
+    /// Stations with unknown pads never match.
+    pub min_pad_size: Option<PadSize>,
+    /// Only fleet carriers (true) or no fleet carriers (false). Carrier markets move, so routes may want to avoid
+    /// them.
+    pub carriers: Option<bool>,
+}
+
+/// Systems whose name starts with the given prefix (case-insensitive), alphabetically
+pub async fn systems_by_name(
+    name: &str,
+    limit: i64,
+    offset: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<System>> {
+    let systems = sqlx::query_as!(
+        System,
+        r#"
+        SELECT id, name, ST_X(coords) AS "x!", ST_Y(coords) AS "y!", ST_Z(coords) AS "z!", populated,
+            NULL::float8 AS distance
+        FROM systems
+        -- the prefix is matched literally, so escape LIKE's wildcards in it
+        WHERE name ILIKE (
+            replace(replace(replace($1::text, '\', '\\'), '%', '\%'), '_', '\_') || '%'
+        ) ESCAPE '\'
+        ORDER BY name
+        LIMIT $2 OFFSET $3;
+    "#,
+        name,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(systems)
+}
+
+/// Systems within the given sphere, nearest to its centre first
+pub async fn systems_near(
+    sphere: Sphere,
+    limit: i64,
+    offset: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<System>> {
+    let systems = sqlx::query_as!(
+        System,
+        r#"
+        SELECT id, name, ST_X(coords) AS "x!", ST_Y(coords) AS "y!", ST_Z(coords) AS "z!", populated,
+            ST_3DDistance(coords, ST_MakePoint($1, $2, $3)) AS distance
+        FROM systems
+        WHERE ST_3DDWithin(coords, ST_MakePoint($1, $2, $3), $4)
+        ORDER BY ST_3DDistance(coords, ST_MakePoint($1, $2, $3))
+        LIMIT $5 OFFSET $6;
+    "#,
+        sphere.x,
+        sphere.y,
+        sphere.z,
+        sphere.radius,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(systems)
+}
+
+/// Stations in a system, closest to the arrival star first
+pub async fn system_stations(
+    system_id: i64,
+    limit: i64,
+    offset: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<Station>> {
+    let stations = sqlx::query_as!(
+        Station,
+        r#"
+        SELECT stat.id, stat.name, stat.market_id, stat.distance_to_arrival, stat.pads_small,
+            stat.pads_medium, stat.pads_large, stat.max_pad_size AS "max_pad_size: PadSize",
+            stat.pad_size_inferred AS "pad_size_inferred!", stat.station_type, stat.planetary, stat.services,
+            stat.primary_economy, stat.government, stat.allegiance, stat.controlling_faction,
+            body.name AS "body_name?", body.gravity AS body_gravity
+        FROM stations stat
+        LEFT JOIN bodies body ON body.id = stat.body_id
+        WHERE stat.system_id = $1
+        ORDER BY stat.distance_to_arrival NULLS LAST, stat.name
+        LIMIT $2 OFFSET $3;
+    "#,
+        system_id,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(stations)
+}
+
+/// Latest market snapshot for a station. Empty if we've never received one.
+pub async fn station_market(market_id: i64, pool: &Pool<Postgres>) -> Result<Vec<Listing>> {
+    let listings = sqlx::query_as!(
+        Listing,
+        r#"
+        SELECT name, mean_price, buy_price, sell_price, demand, demand_bracket, stock, stock_bracket,
+            listed_at
+        FROM latest_listings
+        WHERE market_id = $1
+        ORDER BY name;
+    "#,
+        market_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(listings)
+}
+
+/// Best places to buy or sell a commodity within the given sphere, using each station's latest listing
+pub async fn best_offers(
+    name: &str,
+    sphere: Sphere,
+    kind: TradeKind,
+    filter: &StationFilter,
+    limit: i64,
+    offset: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<Offer>> {
+    let buying = matches!(kind, TradeKind::Buy);
+    let offers = sqlx::query_as!(
+        Offer,
+        r#"
+        SELECT latest.market_id AS "market_id!", stat.name AS station_name,
+            loc.system_name AS "system_name!", c.market_id IS NOT NULL AS "carrier!",
+            stat.max_pad_size AS "max_pad_size: PadSize", stat.pad_size_inferred AS "pad_size_inferred!",
+            stat.distance_to_arrival, stat.planetary, body.gravity AS body_gravity,
+            ST_X(loc.coords) AS "x!", ST_Y(loc.coords) AS "y!", ST_Z(loc.coords) AS "z!",
+            ST_3DDistance(loc.coords, ST_MakePoint($2, $3, $4)) AS "distance!", NULL::int4 AS jumps,
+            latest.buy_price AS "buy_price!", latest.sell_price AS "sell_price!",
+            latest.stock AS "stock!", latest.demand AS "demand!", latest.listed_at AS "listed_at!"
+        FROM latest_listings latest
+        INNER JOIN stations stat ON stat.market_id = latest.market_id
+        LEFT JOIN systems sys ON sys.id = stat.system_id
+        LEFT JOIN bodies body ON body.id = stat.body_id
+        -- carriers are wherever they were last seen
+        LEFT JOIN carriers c ON c.market_id = latest.market_id
+        CROSS JOIN LATERAL (
+            SELECT COALESCE(c.coords, sys.coords) AS coords, COALESCE(c.system_name, sys.name) AS system_name
+        ) loc
+        WHERE latest.name = $1
+        AND ST_3DDWithin(loc.coords, ST_MakePoint($2, $3, $4), $5)
+        AND (($6 AND latest.stock > 0) OR (NOT $6 AND latest.demand > 0))
+        AND ($7::text IS NULL OR stat.services @> ARRAY[$7])
+        AND ($8::bool IS NULL OR stat.planetary = $8)
+        AND ($9::pad_size IS NULL OR stat.max_pad_size >= $9)
+        AND ($10::bool IS NULL OR (c.market_id IS NOT NULL) = $10)
+        ORDER BY CASE WHEN $6 THEN latest.buy_price ELSE -latest.sell_price END
+        LIMIT $11 OFFSET $12;
+    "#,
+        name,
+        sphere.x,
+        sphere.y,
+        sphere.z,
+        sphere.radius,
+        buying,
+        filter.service,
+        filter.planetary,
+        filter.min_pad_size as Option<PadSize>,
+        filter.carriers,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(offers)
+}
+
+/// Price history of a commodity at a station, newest first, optionally only since the given time
+pub async fn price_history(
+    market_id: i64,
+    name: &str,
+    since: Option<NaiveDateTime>,
+    limit: i64,
+    offset: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<PricePoint>> {
+    let history = sqlx::query_as!(
+        PricePoint,
+        r#"
+        SELECT listed_at, mean_price, buy_price, sell_price, demand, stock
+        FROM listings
+        WHERE market_id = $1 AND name = $2
+        AND ($3::timestamp IS NULL OR listed_at >= $3)
+        ORDER BY listed_at DESC
+        LIMIT $4 OFFSET $5;
+    "#,
+        market_id,
+        name,
+        since,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(history)
+}
+
+/// A fleet carrier by market ID
+pub async fn carrier(market_id: i64, pool: &Pool<Postgres>) -> Result<Carrier> {
+    let carrier = sqlx::query_as!(
+        Carrier,
+        r#"
+        SELECT market_id, callsign, name, system_id64, system_name,
+            ST_X(coords) AS x, ST_Y(coords) AS y, ST_Z(coords) AS z, located_at
+        FROM carriers
+        WHERE market_id = $1;
+    "#,
+        market_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(carrier)
+}
+
+/// Systems a fleet carrier has been seen in, most recent first
+pub async fn carrier_positions(
+    market_id: i64,
+    limit: i64,
+    offset: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<CarrierPosition>> {
+    let positions = sqlx::query_as!(
+        CarrierPosition,
+        r#"
+        SELECT system_id64, system_name, ST_X(coords) AS x, ST_Y(coords) AS y, ST_Z(coords) AS z, seen_at
+        FROM carrier_positions
+        WHERE market_id = $1
+        ORDER BY seen_at DESC
+        LIMIT $2 OFFSET $3;
+    "#,
+        market_id,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(positions)
+}
+
+/// Minor factions present in a system, most influential first
+pub async fn system_factions(system_id: i64, pool: &Pool<Postgres>) -> Result<Vec<SystemFaction>> {
+    let factions = sqlx::query_as!(
+        SystemFaction,
+        r#"
+        SELECT f.faction, f.influence, f.state, f.active_states, f.pending_states, f.recovering_states,
+            f.allegiance, f.government, f.happiness, f.updated_at
+        FROM faction_presences f
+        INNER JOIN systems sys ON sys.id64 = f.system_id64
+        WHERE sys.id = $1
+        ORDER BY f.influence DESC NULLS LAST, f.faction;
+    "#,
+        system_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(factions)
+}
+
+/// Changes in the influence and states of the factions in a system, newest first, optionally only since the given
+/// time
+pub async fn faction_history(
+    system_id: i64,
+    since: Option<NaiveDateTime>,
+    limit: i64,
+    offset: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<FactionHistoryPoint>> {
+    let history = sqlx::query_as!(
+        FactionHistoryPoint,
+        r#"
+        SELECT h.faction, h.influence, h.state, h.active_states, h.pending_states, h.recovering_states,
+            h.seen_at
+        FROM faction_presence_history h
+        INNER JOIN systems sys ON sys.id64 = h.system_id64
+        WHERE sys.id = $1
+        AND ($2::timestamp IS NULL OR h.seen_at >= $2)
+        ORDER BY h.seen_at DESC, h.faction
+        LIMIT $3 OFFSET $4;
+    "#,
+        system_id,
+        since,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(history)
+}
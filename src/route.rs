@@ -0,0 +1,573 @@
+// Plots jump routes between systems. Trade profit per hour depends on how many jumps a trip takes rather than the
+// straight-line distance, so routes are plotted with A* over the systems we have coordinates for (import EDSM's
+// full systems dump to route through unpopulated space). Rather than querying for each system's neighbours as we
+// go, the systems in a corridor around the straight line between the ends are loaded at once and bucketed into a
+// grid of jump-range-sized cells, so neighbours are found in memory. If there's no route within the corridor it's
+// widened and we try again.
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+};
+
+use comfy_table::{presets::UTF8_FULL, Table};
+use futures::{StreamExt, TryStreamExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use utoipa::ToSchema;
+
+use crate::{query::Offer, Error, Result};
+
+/// How far either side of the straight line between the ends of a route systems are loaded from, in light years,
+/// on the first attempt. It's at least twice the jump range.
+const INITIAL_CORRIDOR_WIDTH: f64 = 100.0;
+/// Number of times the corridor is doubled in width when there's no route within it, before giving up
+const CORRIDOR_ATTEMPTS: u32 = 3;
+/// Most systems loaded into a corridor when estimating jumps for the API. A denser corridor would take too long
+/// to plot, so the estimate is left out instead.
+const MAX_ESTIMATE_SYSTEMS: i64 = 50_000;
+/// Number of routes plotted at once when estimating jumps for a page of offers
+const CONCURRENT_ESTIMATES: usize = 4;
+
+/// Supercharging the frame shift drive in a star's jet cone, which boosts the jump range
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Supercharge {
+    /// Neutron star, quadruples the jump range
+    Neutron,
+    /// White dwarf, boosts the jump range by half
+    WhiteDwarf,
+}
+
+impl Supercharge {
+    pub fn multiplier(self) -> f64 {
+        match self {
+            Supercharge::Neutron => 4.0,
+            Supercharge::WhiteDwarf => 1.5,
+        }
+    }
+}
+
+/// How far a ship can jump
+#[derive(Debug, Clone, Copy)]
+pub struct JumpRange {
+    /// Laden jump range in light years
+    pub range: f64,
+    /// Supercharge every jump. We don't store star classes, so this assumes there's a suitable star everywhere
+    /// along the route, making it a best case (like a neutron highway) rather than a real plot.
+    pub supercharge: Option<Supercharge>,
+}
+
+impl JumpRange {
+    /// Distance a single jump can cover, including any supercharge
+    pub fn effective(&self) -> f64 {
+        self.range * self.supercharge.map_or(1.0, Supercharge::multiplier)
+    }
+}
+
+/// A system along a route
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct RouteSystem {
+    pub id: i64,
+    pub name: String,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl RouteSystem {
+    fn coords(&self) -> [f64; 3] {
+        [self.x, self.y, self.z]
+    }
+}
+
+/// A plotted route, from the system nearest the start to the system nearest the end
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Route {
+    /// Systems visited, including the first and last
+    pub systems: Vec<RouteSystem>,
+    /// Total distance jumped in light years
+    pub distance: f64,
+}
+
+impl Route {
+    pub fn jumps(&self) -> usize {
+        self.systems.len() - 1
+    }
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Systems in a corridor, bucketed into cells one jump wide so that a system's neighbours are all in the 27
+/// cells around it
+struct Corridor {
+    systems: Vec<RouteSystem>,
+    cells: HashMap<[i64; 3], Vec<usize>>,
+    cell_size: f64,
+}
+
+impl Corridor {
+    fn new(systems: Vec<RouteSystem>, cell_size: f64) -> Self {
+        let mut cells: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+        for (index, system) in systems.iter().enumerate() {
+            cells
+                .entry(Self::cell(system.coords(), cell_size))
+                .or_default()
+                .push(index);
+        }
+        Corridor {
+            systems,
+            cells,
+            cell_size,
+        }
+    }
+
+    fn cell(coords: [f64; 3], cell_size: f64) -> [i64; 3] {
+        coords.map(|c| (c / cell_size).floor() as i64)
+    }
+
+    /// Index of the system nearest the given point
+    fn nearest(&self, point: [f64; 3]) -> Option<usize> {
+        (0..self.systems.len()).min_by(|&a, &b| {
+            distance(self.systems[a].coords(), point)
+                .total_cmp(&distance(self.systems[b].coords(), point))
+        })
+    }
+
+    /// Index of the system nearest the given point, if it's within `range` of it. A point further than a jump
+    /// from any system we know of can't be routed from or to.
+    fn nearest_within(&self, point: [f64; 3], range: f64) -> Option<usize> {
+        self.nearest(point)
+            .filter(|&index| distance(self.systems[index].coords(), point) <= range)
+    }
+
+    /// Systems within `range` of the given system, with their distances
+    fn neighbours(&self, index: usize, range: f64) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let coords = self.systems[index].coords();
+        let [cx, cy, cz] = Self::cell(coords, self.cell_size);
+        (-1..=1)
+            .flat_map(move |dx| {
+                (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| [cx + dx, cy + dy, cz + dz]))
+            })
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .filter(move |&&other| other != index)
+            .map(move |&other| (other, distance(coords, self.systems[other].coords())))
+            .filter(move |&(_, jump)| jump <= range)
+    }
+}
+
+/// Cost of reaching a system: fewest jumps, then shortest distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cost {
+    jumps: u32,
+    distance: f64,
+}
+
+impl Eq for Cost {}
+
+impl Ord for Cost {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.jumps
+            .cmp(&other.jumps)
+            .then(self.distance.total_cmp(&other.distance))
+    }
+}
+
+impl PartialOrd for Cost {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A system waiting to be expanded, ordered so that BinaryHeap pops the lowest estimated total cost first
+#[derive(PartialEq, Eq)]
+struct Open {
+    estimate: Cost,
+    index: usize,
+}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.cmp(&self.estimate)
+    }
+}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A* from `start` to `goal` within the corridor. The heuristic (jumps needed in a straight line, then the
+/// straight-line distance) never overestimates, so the route found has the fewest jumps.
+fn a_star(corridor: &Corridor, start: usize, goal: usize, range: f64) -> Option<Route> {
+    let goal_coords = corridor.systems[goal].coords();
+    let heuristic = |index: usize, cost: Cost| {
+        let remaining = distance(corridor.systems[index].coords(), goal_coords);
+        Cost {
+            jumps: cost.jumps + (remaining / range).ceil() as u32,
+            distance: cost.distance + remaining,
+        }
+    };
+
+    let mut best: HashMap<usize, Cost> = HashMap::new();
+    let mut came_from: HashMap<usize, usize> = HashMap::new();
+    let mut open = BinaryHeap::new();
+    let start_cost = Cost {
+        jumps: 0,
+        distance: 0.0,
+    };
+    best.insert(start, start_cost);
+    open.push(Open {
+        estimate: heuristic(start, start_cost),
+        index: start,
+    });
+
+    while let Some(Open { estimate, index }) = open.pop() {
+        let cost = best[&index];
+        if heuristic(index, cost) < estimate {
+            // stale entry, we've since found a cheaper way here
+            continue;
+        }
+        if index == goal {
+            let mut path = vec![goal];
+            while let Some(&previous) = came_from.get(path.last().unwrap()) {
+                path.push(previous);
+            }
+            path.reverse();
+            return Some(Route {
+                systems: path
+                    .into_iter()
+                    .map(|index| corridor.systems[index].clone())
+                    .collect(),
+                distance: cost.distance,
+            });
+        }
+
+        for (neighbour, jump) in corridor.neighbours(index, range) {
+            let next = Cost {
+                jumps: cost.jumps + 1,
+                distance: cost.distance + jump,
+            };
+            if best.get(&neighbour).is_none_or(|&known| next < known) {
+                best.insert(neighbour, next);
+                came_from.insert(neighbour, index);
+                open.push(Open {
+                    estimate: heuristic(neighbour, next),
+                    index: neighbour,
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Loads the systems within `width` light years of the straight line between two points, up to `limit` of them.
+/// The line is covered by spheres so that the lookup can use the GIST index on coords.
+async fn load_corridor(
+    from: [f64; 3],
+    to: [f64; 3],
+    width: f64,
+    limit: Option<i64>,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<RouteSystem>, Error> {
+    let steps = (distance(from, to) / width).ceil().max(1.0) as usize;
+    let centres: Vec<[f64; 3]> = (0..=steps)
+        .map(|step| {
+            let t = step as f64 / steps as f64;
+            [0, 1, 2].map(|axis| from[axis] + (to[axis] - from[axis]) * t)
+        })
+        .collect();
+    // spheres this big around centres `width` apart cover the whole cylinder around the line
+    let radius = width * 5f64.sqrt() / 2.0;
+
+    let systems = sqlx::query_as!(
+        RouteSystem,
+        r#"
+        SELECT DISTINCT ON (sys.id)
+            sys.id, sys.name, ST_X(sys.coords) AS "x!", ST_Y(sys.coords) AS "y!", ST_Z(sys.coords) AS "z!"
+        FROM unnest($1::float8[], $2::float8[], $3::float8[]) AS centre(x, y, z)
+        INNER JOIN systems sys ON ST_3DDWithin(sys.coords, ST_MakePoint(centre.x, centre.y, centre.z), $4)
+        ORDER BY sys.id
+        LIMIT $5;
+    "#,
+        &centres.iter().map(|c| c[0]).collect::<Vec<_>>(),
+        &centres.iter().map(|c| c[1]).collect::<Vec<_>>(),
+        &centres.iter().map(|c| c[2]).collect::<Vec<_>>(),
+        radius,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(systems)
+}
+
+/// Plots the route with the fewest jumps from the system nearest `from` to the system nearest `to`. Returns None
+/// if there's no route, e.g. because the jump range is too short to cross a gap between systems we know of, or
+/// if a corridor would have more than `max_systems` systems.
+pub async fn plot_route(
+    from: [f64; 3],
+    to: [f64; 3],
+    jump_range: &JumpRange,
+    max_systems: Option<i64>,
+    pool: &Pool<Postgres>,
+) -> Result<Option<Route>, Error> {
+    let range = jump_range.effective();
+    let mut width = INITIAL_CORRIDOR_WIDTH.max(range * 2.0);
+    for _ in 0..CORRIDOR_ATTEMPTS {
+        // one more than the maximum, to tell whether it was reached
+        let systems = load_corridor(from, to, width, max_systems.map(|max| max + 1), pool).await?;
+        if max_systems.is_some_and(|max| systems.len() as i64 > max) {
+            return Ok(None);
+        }
+        let corridor = Corridor::new(systems, range);
+        let (Some(start), Some(goal)) = (
+            corridor.nearest_within(from, range),
+            corridor.nearest_within(to, range),
+        ) else {
+            return Ok(None);
+        };
+        if let Some(route) = a_star(&corridor, start, goal, range) {
+            return Ok(Some(route));
+        }
+        width *= 2.0;
+    }
+    Ok(None)
+}
+
+/// Estimates the number of jumps between two points, or None if there's no route or the space between them is
+/// too dense to plot one quickly
+pub async fn estimate_jumps(
+    from: [f64; 3],
+    to: [f64; 3],
+    jump_range: &JumpRange,
+    pool: &Pool<Postgres>,
+) -> Result<Option<usize>, Error> {
+    Ok(plot_route(from, to, jump_range, Some(MAX_ESTIMATE_SYSTEMS), pool)
+        .await?
+        .map(|route| route.jumps()))
+}
+
+/// Fills in the estimated number of jumps from `from` to each offer's system. This plots a route per offer, a few
+/// at a time, so it's meant for a page of offers rather than a whole result set.
+pub async fn estimate_offer_jumps(
+    offers: &mut [Offer],
+    from: [f64; 3],
+    jump_range: &JumpRange,
+    pool: &Pool<Postgres>,
+) -> Result<(), Error> {
+    let destinations: Vec<[f64; 3]> = offers.iter().map(Offer::coords).collect();
+    let estimates: Vec<Option<usize>> = futures::stream::iter(destinations)
+        .map(|to| estimate_jumps(from, to, jump_range, pool))
+        .buffered(CONCURRENT_ESTIMATES)
+        .try_collect()
+        .await?;
+    for (offer, jumps) in offers.iter_mut().zip(estimates) {
+        offer.jumps = jumps.map(|jumps| jumps as i32);
+    }
+    Ok(())
+}
+
+/// Looks up a system's coordinates by its exact name (case-insensitive)
+async fn system_coords(name: &str, pool: &Pool<Postgres>) -> Result<Option<[f64; 3]>, Error> {
+    let system = sqlx::query!(
+        r#"
+        SELECT ST_X(coords) AS "x!", ST_Y(coords) AS "y!", ST_Z(coords) AS "z!"
+        FROM systems
+        WHERE lower(name) = lower($1)
+        LIMIT 1;
+    "#,
+        name
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(system.map(|row| [row.x, row.y, row.z]))
+}
+
+pub async fn plot(
+    from: String,
+    to: String,
+    jump_range: JumpRange,
+    url: String,
+    pool_size: u32,
+) -> Result<()> {
+    if !jump_range.range.is_finite() || jump_range.range <= 0.0 {
+        return Err(Error::InvalidArgument(
+            "Jump range must be a positive number".to_string(),
+        ));
+    }
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    let from_coords = system_coords(&from, &pool)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No system named {}", from)))?;
+    let to_coords = system_coords(&to, &pool)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No system named {}", to)))?;
+
+    info!(
+        "Plotting route from {} to {} with a {} ly jump range",
+        from,
+        to,
+        jump_range.effective()
+    );
+    let route = plot_route(from_coords, to_coords, &jump_range, None, &pool)
+        .await?
+        .ok_or_else(|| {
+            Error::NotFound(format!(
+                "No route from {} to {} within jump range",
+                from, to
+            ))
+        })?;
+
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL);
+    table.set_header(vec!["Jump", "System", "Distance (ly)", "Total (ly)"]);
+    table.add_row(vec!["", &route.systems[0].name, "", ""]);
+    let mut total = 0.0;
+    for (jump, pair) in route.systems.windows(2).enumerate() {
+        let length = distance(pair[0].coords(), pair[1].coords());
+        total += length;
+        table.add_row(vec![
+            (jump + 1).to_string(),
+            pair[1].name.clone(),
+            format!("{:.2}", length),
+            format!("{:.2}", total),
+        ]);
+    }
+    println!("{}", table);
+    info!(
+        "{} jumps, {:.2} ly ({:.2} ly in a straight line)",
+        route.jumps(),
+        route.distance,
+        distance(from_coords, to_coords)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system(id: i64, x: f64, y: f64, z: f64) -> RouteSystem {
+        RouteSystem {
+            id,
+            name: format!("System {}", id),
+            x,
+            y,
+            z,
+        }
+    }
+
+    /// Systems every 10 ly along the x axis
+    fn line(count: i64) -> Vec<RouteSystem> {
+        (0..count)
+            .map(|i| system(i, i as f64 * 10.0, 0.0, 0.0))
+            .collect()
+    }
+
+    fn ids(route: &Route) -> Vec<i64> {
+        route.systems.iter().map(|system| system.id).collect()
+    }
+
+    #[test]
+    fn effective_range_applies_supercharge() {
+        let mut jump_range = JumpRange {
+            range: 20.0,
+            supercharge: None,
+        };
+        assert_eq!(jump_range.effective(), 20.0);
+        jump_range.supercharge = Some(Supercharge::WhiteDwarf);
+        assert_eq!(jump_range.effective(), 30.0);
+        jump_range.supercharge = Some(Supercharge::Neutron);
+        assert_eq!(jump_range.effective(), 80.0);
+    }
+
+    #[test]
+    fn corridor_finds_neighbours_across_cells() {
+        let corridor = Corridor::new(
+            vec![
+                system(0, 0.0, 0.0, 0.0),
+                // just over a cell boundary
+                system(1, -9.0, 0.0, 0.0),
+                system(2, 0.0, 10.0, 5.0),
+                // in a neighbouring cell but out of range
+                system(3, 15.0, 15.0, 0.0),
+                system(4, 100.0, 0.0, 0.0),
+            ],
+            15.0,
+        );
+        let mut neighbours: Vec<usize> = corridor
+            .neighbours(0, 15.0)
+            .map(|(index, _)| index)
+            .collect();
+        neighbours.sort();
+        assert_eq!(neighbours, vec![1, 2]);
+        assert!(corridor.neighbours(4, 15.0).next().is_none());
+    }
+
+    #[test]
+    fn corridor_finds_nearest_system() {
+        let corridor = Corridor::new(line(5), 10.0);
+        assert_eq!(corridor.nearest([23.0, 4.0, -2.0]), Some(2));
+        assert_eq!(corridor.nearest([-500.0, 0.0, 0.0]), Some(0));
+        assert_eq!(
+            Corridor::new(Vec::new(), 10.0).nearest([0.0, 0.0, 0.0]),
+            None
+        );
+    }
+
+    #[test]
+    fn corridor_rejects_points_out_of_range() {
+        let corridor = Corridor::new(line(5), 10.0);
+        assert_eq!(corridor.nearest_within([23.0, 4.0, -2.0], 10.0), Some(2));
+        assert_eq!(corridor.nearest_within([40.0, 10.0, 0.0], 10.0), Some(4));
+        assert_eq!(corridor.nearest_within([40.0, 10.1, 0.0], 10.0), None);
+        assert_eq!(corridor.nearest_within([-500.0, 0.0, 0.0], 10.0), None);
+    }
+
+    #[test]
+    fn a_star_takes_fewest_jumps() {
+        let corridor = Corridor::new(line(7), 25.0);
+        let route = a_star(&corridor, 0, 6, 25.0).unwrap();
+        assert_eq!(ids(&route), vec![0, 2, 4, 6]);
+        assert_eq!(route.jumps(), 3);
+        assert_eq!(route.distance, 60.0);
+
+        let corridor = Corridor::new(line(7), 10.0);
+        assert_eq!(a_star(&corridor, 0, 6, 10.0).unwrap().jumps(), 6);
+    }
+
+    #[test]
+    fn a_star_prefers_shorter_routes_with_equal_jumps() {
+        let corridor = Corridor::new(
+            vec![
+                system(0, 0.0, 0.0, 0.0),
+                system(1, 10.0, 8.0, 0.0),
+                system(2, 10.0, 1.0, 0.0),
+                system(3, 20.0, 0.0, 0.0),
+            ],
+            15.0,
+        );
+        let route = a_star(&corridor, 0, 3, 15.0).unwrap();
+        assert_eq!(ids(&route), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn a_star_handles_unreachable_and_trivial_routes() {
+        let mut systems = line(3);
+        systems.push(system(3, 100.0, 0.0, 0.0));
+        let corridor = Corridor::new(systems, 15.0);
+        assert!(a_star(&corridor, 0, 3, 15.0).is_none());
+
+        let route = a_star(&corridor, 1, 1, 15.0).unwrap();
+        assert_eq!(ids(&route), vec![1]);
+        assert_eq!(route.jumps(), 0);
+    }
+}
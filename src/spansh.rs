@@ -1,106 +1,450 @@
-// Imports Spansh's data dumps (https://spansh.co.uk/dumps) to determine station landing pads
+// Imports Spansh's data dumps (https://spansh.co.uk/dumps) to fill in station details that EDSM doesn't have:
+// landing pads, station type, services, economies, government, allegiance and controlling faction.
 // Specifically: https://downloads.spansh.co.uk/galaxy_stations.json.gz
+//
+// Stations are matched by market ID, falling back to their name in the system with the same id64. Anything that
+// can't be matched is returned in a report, rather than silently dropped.
+//
+// The dump also embeds each station's last known market, which can optionally be imported into the listings
+// to cover stations nobody has visited since the EDDN listener started. Spansh doesn't include the galactic
+// average price or the stock/demand brackets: the average is taken from the latest listings of the same
+// commodity elsewhere (or 0 if there are none), and the brackets are stored as 0.
 
-use color_eyre::eyre::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+};
+
+use chrono::{DateTime, Datelike, NaiveDateTime};
 use indicatif::ProgressIterator;
 use log::{info, warn};
-use serde::Deserialize;
-use sqlx::postgres::PgPoolOptions;
-use std::{fs::File, io::BufReader};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{latest, maintenance, Result};
 
-// This is a very condensed schema to only process the landing pad data
+/// Format of timestamps in Spansh's dumps, e.g. "2024-07-29 03:20:31+00"
+const SPANSH_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%#z";
+
+/// Deserializes an optional Spansh timestamp into UTC
+fn spansh_time<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<NaiveDateTime>, D::Error> {
+    let Some(time) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    DateTime::parse_from_str(&time, SPANSH_TIME_FORMAT)
+        .map(|time| Some(time.naive_utc()))
+        .map_err(serde::de::Error::custom)
+}
+
+// Only the parts of the schema we store are deserialized; serde skips the rest (outfitting, shipyard, ...)
 
 #[derive(Deserialize, Debug)]
 struct SpanshLandingPads {
-    large: i32,
-    medium: i32,
-    small: i32,
+    large: i16,
+    medium: i16,
+    small: i16,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SpanshCommodity {
+    /// Internal name, e.g. "AgronomicTreatment". EDDN uses the same name in lower case.
+    symbol: String,
+    buy_price: i32,
+    sell_price: i32,
+    demand: i32,
+    supply: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SpanshMarket {
+    #[serde(default)]
+    commodities: Vec<SpanshCommodity>,
+    #[serde(default, deserialize_with = "spansh_time")]
+    update_time: Option<NaiveDateTime>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct SpanshStation {
+    /// Spansh's station ID, which is the market ID
+    #[serde(alias = "marketId")]
+    id: Option<i64>,
     name: String,
+    #[serde(rename = "type")]
+    typ: Option<String>,
+    distance_to_arrival: Option<f32>,
     landing_pads: Option<SpanshLandingPads>,
+    #[serde(default)]
+    services: Vec<String>,
+    primary_economy: Option<String>,
+    #[serde(default)]
+    economies: HashMap<String, f64>,
+    government: Option<String>,
+    allegiance: Option<String>,
+    controlling_faction: Option<String>,
+    market: Option<SpanshMarket>,
+    #[serde(default, deserialize_with = "spansh_time")]
+    update_time: Option<NaiveDateTime>,
+}
+
+impl SpanshStation {
+    /// Whether the station is on a planet's surface, going by its type. Planetary ports, outposts and Odyssey
+    /// settlements are all named as such (e.g. "Planetary Port", "Settlement").
+    fn planetary(&self) -> Option<bool> {
+        self.typ
+            .as_ref()
+            .map(|typ| typ.contains("Planetary") || typ.contains("Settlement"))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SpanshBody {
+    #[serde(default)]
+    stations: Vec<SpanshStation>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct SpanshSystem {
+    id64: Option<i64>,
     name: String,
+    #[serde(default)]
     stations: Vec<SpanshStation>,
+    /// Surface stations are listed under the body they're on, not the system
+    #[serde(default)]
+    bodies: Vec<SpanshBody>,
 }
 
+impl SpanshSystem {
+    fn all_stations(&self) -> impl Iterator<Item = &SpanshStation> {
+        self.stations
+            .iter()
+            .chain(self.bodies.iter().flat_map(|body| &body.stations))
+    }
+}
+
+/// Imports a station's market snapshot into the listings using its original timestamp, skipping the
+/// commodities we already have the same or newer data for. Returns the number of listings inserted.
+async fn import_market(
+    market_id: i64,
+    market: &SpanshMarket,
+    partitions: &mut HashSet<(i32, u32)>,
+    pool: &Pool<Postgres>,
+) -> Result<u64> {
+    let Some(time) = market.update_time else {
+        return Ok(0);
+    };
+
+    // snapshots can be years old, so make sure the month has a partition
+    if partitions.insert((time.year(), time.month())) {
+        maintenance::create_partition(time.date(), pool).await?;
+    }
+
+    let mut transaction = pool.begin().await?;
+    let mut inserted = 0;
+    for commodity in &market.commodities {
+        let count = sqlx::query_scalar!(
+            r#"
+            WITH inserted AS (
+                INSERT INTO listings (
+                    market_id,
+                    name,
+                    mean_price,
+                    buy_price,
+                    sell_price,
+                    demand,
+                    demand_bracket,
+                    stock,
+                    stock_bracket,
+                    listed_at
+                )
+                SELECT $1, $2::text,
+                    COALESCE((SELECT ROUND(AVG(mean_price))::int FROM latest_listings WHERE name = $2), 0),
+                    $3, $4, $5, 0, $6, 0, $7
+                -- checked against the history rather than latest_listings, since a newer snapshot prunes the
+                -- commodities it no longer lists
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM listings WHERE market_id = $1 AND name = $2 AND listed_at >= $7
+                )
+                ON CONFLICT DO NOTHING
+                RETURNING *
+            ), latest AS (
+                INSERT INTO latest_listings (
+                    market_id,
+                    name,
+                    mean_price,
+                    buy_price,
+                    sell_price,
+                    demand,
+                    demand_bracket,
+                    stock,
+                    stock_bracket,
+                    listed_at
+                )
+                SELECT market_id, name, mean_price, buy_price, sell_price, demand,
+                    demand_bracket, stock, stock_bracket, listed_at
+                FROM inserted
+                -- a commodity missing from a newer snapshot of the market mustn't come back
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM latest_listings WHERE market_id = $1 AND listed_at > $7
+                )
+                ON CONFLICT (market_id, name) DO UPDATE SET
+                    mean_price = EXCLUDED.mean_price,
+                    buy_price = EXCLUDED.buy_price,
+                    sell_price = EXCLUDED.sell_price,
+                    demand = EXCLUDED.demand,
+                    demand_bracket = EXCLUDED.demand_bracket,
+                    stock = EXCLUDED.stock,
+                    stock_bracket = EXCLUDED.stock_bracket,
+                    listed_at = EXCLUDED.listed_at
+                WHERE latest_listings.listed_at <= EXCLUDED.listed_at
+            )
+            SELECT COUNT(*) AS "count!" FROM inserted;
+        "#,
+            market_id,
+            commodity.symbol.to_lowercase(),
+            commodity.buy_price,
+            commodity.sell_price,
+            commodity.demand,
+            commodity.supply,
+            time
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        inserted += count as u64;
+    }
+    transaction.commit().await?;
+
+    // like a market update from EDDN, this snapshot replaces any older one
+    if inserted > 0 {
+        latest::prune_market(market_id, time, pool).await?;
+    }
+
+    Ok(inserted)
+}
+
+/// The details of a Spansh station to update, as a row of the batch passed to `jsonb_to_recordset`
+#[derive(Serialize)]
+struct StationUpdate<'a> {
+    /// Position in the batch, to tell which stations were matched
+    idx: usize,
+    market_id: Option<i64>,
+    system_id64: Option<i64>,
+    name: &'a str,
+    pads_small: Option<i16>,
+    pads_medium: Option<i16>,
+    pads_large: Option<i16>,
+    distance_to_arrival: Option<f32>,
+    station_type: Option<&'a str>,
+    planetary: Option<bool>,
+    services: Option<&'a [String]>,
+    primary_economy: Option<&'a str>,
+    economies: Option<&'a HashMap<String, f64>>,
+    government: Option<&'a str>,
+    allegiance: Option<&'a str>,
+    controlling_faction: Option<&'a str>,
+    details_updated_at: Option<NaiveDateTime>,
+    market_updated_at: Option<NaiveDateTime>,
+}
+
+impl<'a> StationUpdate<'a> {
+    fn new(idx: usize, system: &SpanshSystem, station: &'a SpanshStation) -> Self {
+        StationUpdate {
+            idx,
+            market_id: station.id,
+            system_id64: system.id64,
+            name: &station.name,
+            pads_small: station.landing_pads.as_ref().map(|pads| pads.small),
+            pads_medium: station.landing_pads.as_ref().map(|pads| pads.medium),
+            pads_large: station.landing_pads.as_ref().map(|pads| pads.large),
+            distance_to_arrival: station.distance_to_arrival,
+            station_type: station.typ.as_deref(),
+            planetary: station.planetary(),
+            services: (!station.services.is_empty()).then_some(station.services.as_slice()),
+            primary_economy: station.primary_economy.as_deref(),
+            economies: (!station.economies.is_empty()).then_some(&station.economies),
+            government: station.government.as_deref(),
+            allegiance: station.allegiance.as_deref(),
+            controlling_faction: station.controlling_faction.as_deref(),
+            details_updated_at: station.update_time,
+            market_updated_at: station
+                .market
+                .as_ref()
+                .and_then(|market| market.update_time),
+        }
+    }
+}
+
+/// A Spansh system with no system of the same id64 in the database
+#[derive(Serialize, Debug)]
+pub struct UnmatchedSystem {
+    pub id64: Option<i64>,
+    pub name: String,
+}
+
+/// A Spansh station that matched neither a station's market ID nor a station of the same name in its system
+#[derive(Serialize, Debug)]
+pub struct UnmatchedStation {
+    pub market_id: Option<i64>,
+    pub name: String,
+    pub system_id64: Option<i64>,
+    pub system_name: String,
+}
+
+/// What couldn't be matched when importing a Spansh dump
+#[derive(Serialize, Debug, Default)]
+pub struct SpanshReport {
+    pub unmatched_systems: Vec<UnmatchedSystem>,
+    pub unmatched_stations: Vec<UnmatchedStation>,
+}
+
+/// Updates station details from Spansh's galaxy stations dump, `batch_size` systems per transaction. If
+/// `import_markets` is set, also imports each station's market snapshot into the listings (see `import_market`).
+/// Returns the systems and stations that couldn't be matched.
 pub async fn read_spansh_stations(
     galaxy_stations_json_path: std::path::PathBuf,
-    url: String,
-) -> Result<()> {
-    info!("Setting up PostgreSQL pool");
-    let var_name = PgPoolOptions::new();
-    let pool = var_name.max_connections(64).connect(&url).await?;
-
+    import_markets: bool,
+    batch_size: usize,
+    pool: &Pool<Postgres>,
+) -> Result<SpanshReport> {
     info!("Parsing Spansh dump");
     let galaxy_file = File::open(galaxy_stations_json_path)?;
     let galaxy_reader = BufReader::new(galaxy_file);
     let systems: Vec<SpanshSystem> = serde_json::from_reader(galaxy_reader)?;
     info!("Parsed {} systems", systems.len());
 
-    // let trans = pool.begin().await?;
+    let chunks = systems.chunks(batch_size);
+    info!("Will update {} chunks", chunks.len());
 
-    for system in systems.iter().progress() {
-        for station in &system.stations {
-            if let Some(landing_pads) = &station.landing_pads {
-                let mut insert = String::new();
-                if landing_pads.small > 0 {
-                    insert += "s,"; // small
-                }
-                if landing_pads.medium > 0 {
-                    insert += "m,"; // medium
-                }
-                if landing_pads.large > 0 {
-                    insert += "l"; // large
-                }
+    let mut report = SpanshReport::default();
+    let mut partitions = HashSet::new();
+    let mut matched = 0;
+    let mut imported = 0;
+
+    for chunk in chunks.progress() {
+        let id64s: Vec<i64> = chunk.iter().filter_map(|system| system.id64).collect();
+        let known: HashSet<i64> = sqlx::query_scalar!(
+            r#"SELECT id64 AS "id64!" FROM systems WHERE id64 = ANY($1);"#,
+            &id64s
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .collect();
+        report.unmatched_systems.extend(
+            chunk
+                .iter()
+                .filter(|system| !system.id64.is_some_and(|id64| known.contains(&id64)))
+                .map(|system| UnmatchedSystem {
+                    id64: system.id64,
+                    name: system.name.clone(),
+                }),
+        );
+
+        let stations: Vec<(&SpanshSystem, &SpanshStation)> = chunk
+            .iter()
+            .flat_map(|system| system.all_stations().map(move |station| (system, station)))
+            .collect();
+        let updates: Vec<StationUpdate> = stations
+            .iter()
+            .enumerate()
+            .map(|(idx, (system, station))| StationUpdate::new(idx, system, station))
+            .collect();
 
-                let system_id_result =
-                    sqlx::query!("SELECT id FROM systems WHERE name = $1;", system.name)
-                        .fetch_one(&pool)
-                        .await;
-
-                match system_id_result {
-                    Ok(system_id) => {
-                        let result = sqlx::query!(
-                            r#"
-                            UPDATE stations SET landing_pad = $1
-                            WHERE name = $2 AND system_id = $3;
-                        "#,
-                            insert,
-                            station.name,
-                            system_id.id
-                        )
-                        .execute(&pool)
-                        .await;
-                        if result.is_err() {
-                            warn!(
-                                "Failed to insert station {} in {}: {}",
-                                station.name,
-                                system.name,
-                                result.unwrap_err()
-                            );
-                        }
-                    }
-                    Err(error) => {
-                        // warn!(
-                        //     "Unable to locate ID for system name {}: {}",
-                        //     system.name, error
-                        // );
-                        continue;
-                    }
+        // keep what we already know if Spansh doesn't have it
+        let mut transaction = pool.begin().await?;
+        let updated = sqlx::query!(
+            r#"
+            WITH updates AS (
+                SELECT * FROM jsonb_to_recordset($1) AS u(
+                    idx INT, market_id BIGINT, system_id64 BIGINT, name TEXT, pads_small SMALLINT,
+                    pads_medium SMALLINT, pads_large SMALLINT, distance_to_arrival REAL, station_type TEXT,
+                    planetary BOOLEAN, services TEXT[], primary_economy TEXT, economies JSONB,
+                    government TEXT, allegiance TEXT, controlling_faction TEXT, details_updated_at TIMESTAMP,
+                    market_updated_at TIMESTAMP
+                )
+            ),
+            matched AS (
+                SELECT u.*, COALESCE(
+                    (SELECT id FROM stations WHERE market_id = u.market_id),
+                    (
+                        SELECT stat.id FROM stations stat
+                        INNER JOIN systems sys ON sys.id = stat.system_id
+                        WHERE sys.id64 = u.system_id64 AND stat.name = u.name
+                        LIMIT 1
+                    )
+                ) AS station_id
+                FROM updates u
+            )
+            UPDATE stations stat SET
+                pads_small = COALESCE(u.pads_small, stat.pads_small),
+                pads_medium = COALESCE(u.pads_medium, stat.pads_medium),
+                pads_large = COALESCE(u.pads_large, stat.pads_large),
+                distance_to_arrival = COALESCE(u.distance_to_arrival, stat.distance_to_arrival),
+                station_type = COALESCE(u.station_type, stat.station_type),
+                planetary = COALESCE(u.planetary, stat.planetary),
+                services = COALESCE(u.services, stat.services),
+                primary_economy = COALESCE(u.primary_economy, stat.primary_economy),
+                economies = COALESCE(u.economies, stat.economies),
+                government = COALESCE(u.government, stat.government),
+                allegiance = COALESCE(u.allegiance, stat.allegiance),
+                controlling_faction = COALESCE(u.controlling_faction, stat.controlling_faction),
+                details_updated_at = COALESCE(u.details_updated_at, stat.details_updated_at),
+                market_updated_at = COALESCE(u.market_updated_at, stat.market_updated_at)
+            FROM matched u
+            WHERE stat.id = u.station_id
+            RETURNING u.idx AS "idx!", stat.market_id;
+        "#,
+            serde_json::to_value(&updates)?
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        matched += updated.len();
+
+        let market_ids: HashMap<usize, Option<i64>> = updated
+            .into_iter()
+            .map(|row| (row.idx as usize, row.market_id))
+            .collect();
+        for (idx, (system, station)) in stations.iter().enumerate() {
+            let Some(market_id) = market_ids.get(&idx) else {
+                report.unmatched_stations.push(UnmatchedStation {
+                    market_id: station.id,
+                    name: station.name.clone(),
+                    system_id64: system.id64,
+                    system_name: system.name.clone(),
+                });
+                continue;
+            };
+
+            if let (true, Some(market_id), Some(market)) =
+                (import_markets, market_id, &station.market)
+            {
+                match import_market(*market_id, market, &mut partitions, pool).await {
+                    Ok(count) => imported += count,
+                    Err(error) => warn!(
+                        "Failed to import market of station {} in {}: {}",
+                        station.name, system.name, error
+                    ),
                 }
             }
         }
     }
 
-    // trans.commit().await?;
+    info!(
+        "Updated {} stations; {} stations and {} systems unmatched",
+        matched,
+        report.unmatched_stations.len(),
+        report.unmatched_systems.len()
+    );
+    if import_markets {
+        info!("Imported {} listings", imported);
+    }
 
-    Ok(())
+    Ok(report)
 }
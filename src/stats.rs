@@ -1,9 +1,182 @@
-use color_eyre::eyre::Result;
+use chrono::{NaiveDate, NaiveDateTime};
 use comfy_table::{presets::UTF8_FULL, Table};
-use log::info;
+use log::{info, warn};
 use num2english::NumberToEnglish;
 use num_format::{Locale, ToFormattedString};
-use sqlx::postgres::PgPoolOptions;
+use serde::Serialize;
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use utoipa::ToSchema;
+
+use crate::Result;
+
+/// Output format for `display_stats`
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum StatsFormat {
+    /// Human readable table
+    Table,
+    /// JSON object with raw numeric values
+    Json,
+    /// `statistic,value` rows with raw numeric values
+    Csv,
+}
+
+/// Shown in place of statistics that failed to compute or that have no data yet
+const NOT_AVAILABLE: &str = "n/a";
+
+/// Statistics about the contents of the database. Each statistic is None if it could not be computed (see
+/// `errors`) or, for single items, if there is no data for it yet.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct Stats {
+    pub systems: Option<i64>,
+    pub stations: Option<i64>,
+    pub stations_updated_24h: Option<i64>,
+    pub listings: Option<i64>,
+    pub listings_24h: Option<i64>,
+    /// Number of stations that have at least one listing
+    pub unique_stations: Option<i64>,
+    /// Cost in credits to buy every commodity in stock across the galaxy, using the latest listings
+    pub galaxy_cost: Option<u64>,
+    pub most_expensive: Option<ItemStat>,
+    pub most_numerous: Option<ItemStat>,
+    /// Number of distinct commodity names currently listed by at least one market
+    pub unique_commodities: Option<i64>,
+    /// Number of (station, commodity) pairs currently listed
+    pub tracked_commodities: Option<i64>,
+    /// Stations with the most commodities currently listed, i.e. the largest markets
+    pub top_stations: Option<Vec<StationStat>>,
+    pub freshness: Option<Freshness>,
+    /// Listings received per day over the last 30 days, oldest first
+    pub listings_per_day: Option<Vec<DailyListings>>,
+    pub pad_coverage: Option<Vec<PadCoverage>>,
+    /// Statistics that failed to compute, and why
+    pub errors: Vec<StatError>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct StatError {
+    pub statistic: String,
+    pub error: String,
+}
+
+/// A station and how many commodities its market currently lists
+#[derive(Serialize, ToSchema, Debug)]
+pub struct StationStat {
+    pub station: String,
+    pub system: String,
+    pub listings: i64,
+}
+
+/// Number of markets by the age of their last update. The buckets don't overlap.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct Freshness {
+    /// Updated in the last hour
+    pub last_hour: i64,
+    /// Updated between 1 and 24 hours ago
+    pub last_day: i64,
+    /// Updated between 1 and 7 days ago
+    pub last_week: i64,
+    /// Last updated more than 7 days ago
+    pub older: i64,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct DailyListings {
+    pub day: NaiveDate,
+    pub listings: i64,
+}
+
+/// How many stations with a given maximum landing pad size we have market data for
+#[derive(Serialize, ToSchema, Debug)]
+pub struct PadCoverage {
+    /// "L", "M", "S" or "unknown"
+    pub pad: String,
+    pub stations: i64,
+    pub stations_with_listings: i64,
+}
+
+/// A single commodity listing, used for the "most X item" statistics
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ItemStat {
+    pub commodity: String,
+    pub station: String,
+    pub system: String,
+    pub buy_price: i32,
+    pub stock: i32,
+    pub listed_at: NaiveDateTime,
+}
+
+impl ItemStat {
+    fn rows(item: &Option<ItemStat>, prefix: &str) -> Vec<(String, String)> {
+        vec![
+            (
+                format!("{prefix}.commodity"),
+                raw(&item.as_ref().map(|i| &i.commodity)),
+            ),
+            (
+                format!("{prefix}.station"),
+                raw(&item.as_ref().map(|i| &i.station)),
+            ),
+            (
+                format!("{prefix}.system"),
+                raw(&item.as_ref().map(|i| &i.system)),
+            ),
+            (
+                format!("{prefix}.buy_price"),
+                raw(&item.as_ref().map(|i| i.buy_price)),
+            ),
+            (
+                format!("{prefix}.stock"),
+                raw(&item.as_ref().map(|i| i.stock)),
+            ),
+            (
+                format!("{prefix}.listed_at"),
+                raw(&item.as_ref().map(|i| i.listed_at)),
+            ),
+        ]
+    }
+}
+
+/// Formats a raw value for machine-readable output, leaving it empty if it's not available
+fn raw<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(ToString::to_string).unwrap_or_default()
+}
+
+/// Formats a count for display, e.g. "1,234" or "n/a"
+fn count<T: ToFormattedString>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map(|v| v.to_formatted_string(&Locale::en_AU))
+        .unwrap_or_else(|| NOT_AVAILABLE.to_string())
+}
+
+/// Joins one line per item for display, or "n/a" if there are no items
+fn lines<T>(items: &Option<Vec<T>>, line: impl Fn(&T) -> String) -> String {
+    match items {
+        Some(items) if !items.is_empty() => items.iter().map(line).collect::<Vec<_>>().join("\n"),
+        _ => NOT_AVAILABLE.to_string(),
+    }
+}
+
+/// Collects per-statistic errors, so that one failing query doesn't prevent the others from being shown
+#[derive(Default)]
+struct Errors(Vec<StatError>);
+
+impl Errors {
+    /// Returns the value of a statistic's query, or None (recording the error) if it failed
+    fn check<T>(&mut self, statistic: &str, result: Result<T, sqlx::Error>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                warn!("Failed to compute statistic {}: {}", statistic, error);
+                self.0.push(StatError {
+                    statistic: statistic.to_string(),
+                    error: error.to_string(),
+                });
+                None
+            }
+        }
+    }
+}
 
 /// Round to significant digits (rather than digits after the decimal).
 ///
@@ -12,7 +185,7 @@ use sqlx::postgres::PgPoolOptions;
 /// floats, convert to `f64` for this function and back as needed.
 ///
 /// Examples:
-/// ```
+/// ```text
 ///   precision_f64(1.2300, 2)                      // 1.2<f64>
 ///   precision_f64(1.2300_f64, 2)                  // 1.2<f64>
 ///   precision_f64(1.2300_f32 as f64, 2)           // 1.2<f64>
@@ -30,78 +203,49 @@ fn precision_f64(x: f64, decimals: u32) -> f64 {
     }
 }
 
-pub async fn display_stats(url: String) -> Result<()> {
-    info!("Setting up PostgreSQL pool on {}", url);
-    let var_name = PgPoolOptions::new();
-    let pool = var_name.max_connections(8).connect(&url).await?;
-
-    info!("Computing statistics");
-    let num_systems_future = sqlx::query!("SELECT COUNT(*) FROM systems;").fetch_one(&pool);
-    let num_stations_future = sqlx::query!("SELECT COUNT(*) FROM stations;").fetch_one(&pool);
-    let num_listings_future = sqlx::query!("SELECT COUNT(*) FROM listings;").fetch_one(&pool);
+/// Computes statistics for the database behind the given pool. Statistics that fail to compute are reported in
+/// `Stats::errors` rather than failing the whole computation.
+pub async fn compute_stats(pool: &Pool<Postgres>) -> Stats {
+    let num_systems_future = sqlx::query!("SELECT COUNT(*) FROM systems;").fetch_one(pool);
+    let num_stations_future = sqlx::query!("SELECT COUNT(*) FROM stations;").fetch_one(pool);
+    let num_listings_future = sqlx::query!("SELECT COUNT(*) FROM listings;").fetch_one(pool);
     let unique_stations_future =
-        sqlx::query!("SELECT COUNT(DISTINCT market_id) FROM listings;").fetch_one(&pool);
-    let latest_listings_future = sqlx::query!(
+        sqlx::query!("SELECT COUNT(DISTINCT market_id) FROM latest_listings;").fetch_one(pool);
+    let galaxy_cost_future = sqlx::query!(
         r#"
-        WITH latest_listings AS (
-            SELECT
-                market_id,
-                name,
-                MAX(listed_at) AS latest_listed_at
-            FROM
-                listings
-            WHERE stock > 0
-            GROUP BY
-                market_id, name
-        )
-        SELECT
-            l.market_id,
-            l.name,
-            l.mean_price,
-            l.buy_price,
-            l.sell_price,
-            l.demand,
-            l.demand_bracket,
-            l.stock,
-            l.stock_bracket,
-            l.listed_at
-        FROM
-            listings l
-        INNER JOIN
-            latest_listings ll
-        ON
-            l.market_id = ll.market_id
-            AND l.name = ll.name
-            AND l.listed_at = ll.latest_listed_at
-        WHERE l.stock > 0;
+        SELECT COALESCE(SUM(buy_price::int8 * stock), 0)::int8 AS "galaxy_cost!"
+        FROM latest_listings
+        WHERE stock > 0;
     "#
     )
-    .fetch_all(&pool);
-    let most_expensive_future = sqlx::query!(
+    .fetch_one(pool);
+    let most_expensive_future = sqlx::query_as!(
+        ItemStat,
         r#"
-        SELECT stat.name as station_name, sys.name as sys_name, list.buy_price as buy_price,
-            list.stock as stock, list.name as commodity_name, list.listed_at as listed_at
-        FROM listings list
+        SELECT stat.name as station, sys.name as system, list.buy_price as buy_price,
+            list.stock as stock, list.name as commodity, list.listed_at as listed_at
+        FROM latest_listings list
         INNER JOIN stations stat ON list.market_id = stat.market_id
-        INNER JOIN systems sys ON stat.id = sys.id
+        INNER JOIN systems sys ON sys.id = stat.system_id
         WHERE stock > 0
         ORDER BY list.buy_price DESC
         LIMIT 1;
     "#
     )
-    .fetch_one(&pool);
-    let most_numerous_future = sqlx::query!(
+    .fetch_optional(pool);
+    let most_numerous_future = sqlx::query_as!(
+        ItemStat,
         r#"
-        SELECT stat.name as station_name, sys.name as sys_name, list.buy_price as buy_price,
-            list.stock as stock, list.name as commodity_name
-        FROM listings list
+        SELECT stat.name as station, sys.name as system, list.buy_price as buy_price,
+            list.stock as stock, list.name as commodity, list.listed_at as listed_at
+        FROM latest_listings list
         INNER JOIN stations stat ON list.market_id = stat.market_id
-        INNER JOIN systems sys ON stat.id = sys.id
+        INNER JOIN systems sys ON sys.id = stat.system_id
         ORDER BY list.stock DESC
         LIMIT 1;
     "#
     )
-    .fetch_one(&pool);
+    .fetch_optional(pool);
     let listings_last_24h_future = sqlx::query!(
         r#"
         SELECT COUNT(*)
@@ -109,7 +253,7 @@ pub async fn display_stats(url: String) -> Result<()> {
         WHERE listed_at >= NOW() - INTERVAL '24 hours';
     "#
     )
-    .fetch_one(&pool);
+    .fetch_one(pool);
     let stations_updated_24h_future = sqlx::query!(
         r#"
         SELECT COUNT(DISTINCT s.id) AS unique_stations
@@ -118,7 +262,82 @@ pub async fn display_stats(url: String) -> Result<()> {
         WHERE l.listed_at >= NOW() - INTERVAL '24 hours';
         "#
     )
-    .fetch_one(&pool);
+    .fetch_one(pool);
+
+    let commodities_future = sqlx::query!(
+        r#"
+        SELECT COUNT(DISTINCT name) AS "unique_commodities!",
+            COUNT(*) AS "tracked_commodities!"
+        FROM latest_listings;
+    "#
+    )
+    .fetch_one(pool);
+    let top_stations_future = sqlx::query_as!(
+        StationStat,
+        r#"
+        SELECT stat.name AS station, sys.name AS system, counts.listings AS "listings!"
+        FROM (
+            SELECT market_id, COUNT(*) AS listings
+            FROM latest_listings
+            GROUP BY market_id
+            ORDER BY listings DESC
+            LIMIT 5
+        ) counts
+        INNER JOIN stations stat ON stat.market_id = counts.market_id
+        INNER JOIN systems sys ON sys.id = stat.system_id
+        ORDER BY counts.listings DESC;
+    "#
+    )
+    .fetch_all(pool);
+    let freshness_future = sqlx::query_as!(
+        Freshness,
+        r#"
+        WITH last_update AS (
+            SELECT market_id, MAX(listed_at) AS listed_at
+            FROM latest_listings
+            GROUP BY market_id
+        )
+        SELECT
+            COUNT(*) FILTER (WHERE listed_at >= NOW() - INTERVAL '1 hour') AS "last_hour!",
+            COUNT(*) FILTER (WHERE listed_at < NOW() - INTERVAL '1 hour'
+                AND listed_at >= NOW() - INTERVAL '24 hours') AS "last_day!",
+            COUNT(*) FILTER (WHERE listed_at < NOW() - INTERVAL '24 hours'
+                AND listed_at >= NOW() - INTERVAL '7 days') AS "last_week!",
+            COUNT(*) FILTER (WHERE listed_at < NOW() - INTERVAL '7 days') AS "older!"
+        FROM last_update;
+    "#
+    )
+    .fetch_one(pool);
+    let listings_per_day_future = sqlx::query_as!(
+        DailyListings,
+        r#"
+        SELECT days.day::date AS "day!", COUNT(l.market_id) AS "listings!"
+        FROM generate_series(CURRENT_DATE - 29, CURRENT_DATE, INTERVAL '1 day') AS days(day)
+        LEFT JOIN listings l
+            ON l.listed_at >= days.day AND l.listed_at < days.day + INTERVAL '1 day'
+        GROUP BY days.day
+        ORDER BY days.day;
+    "#
+    )
+    .fetch_all(pool);
+    let pad_coverage_future = sqlx::query_as!(
+        PadCoverage,
+        r#"
+        SELECT
+            COALESCE(
+                max_pad_size::text || CASE WHEN pad_size_inferred THEN ' (inferred)' ELSE '' END,
+                'unknown'
+            ) AS "pad!",
+            COUNT(*) AS "stations!",
+            COUNT(*) FILTER (
+                WHERE EXISTS (SELECT 1 FROM latest_listings l WHERE l.market_id = stations.market_id)
+            ) AS "stations_with_listings!"
+        FROM stations
+        GROUP BY 1
+        ORDER BY 1;
+    "#
+    )
+    .fetch_all(pool);
 
     #[rustfmt::skip]
     let (
@@ -126,121 +345,281 @@ pub async fn display_stats(url: String) -> Result<()> {
             num_stations,
             num_listings,
             unique_stations,
-            latest_listings,
+            galaxy_cost,
             most_expensive,
             most_numerous,
             listings_last_24h,
             stations_updated_24h,
+            commodities,
+            top_stations,
+            freshness,
+            listings_per_day,
+            pad_coverage,
         )
     = futures::join!(
         num_systems_future,
         num_stations_future,
         num_listings_future,
         unique_stations_future,
-        latest_listings_future,
+        galaxy_cost_future,
         most_expensive_future,
         most_numerous_future,
         listings_last_24h_future,
         stations_updated_24h_future,
+        commodities_future,
+        top_stations_future,
+        freshness_future,
+        listings_per_day_future,
+        pad_coverage_future,
     );
 
-    let unique_stations_count = unique_stations.unwrap().count.unwrap();
-    let total_stations_count = num_stations.unwrap().count.unwrap();
+    let mut errors = Errors::default();
+    let commodities = errors.check("commodities", commodities);
+
+    Stats {
+        systems: errors.check("systems", num_systems).and_then(|r| r.count),
+        stations: errors.check("stations", num_stations).and_then(|r| r.count),
+        stations_updated_24h: errors
+            .check("stations_updated_24h", stations_updated_24h)
+            .and_then(|r| r.unique_stations),
+        listings: errors.check("listings", num_listings).and_then(|r| r.count),
+        listings_24h: errors
+            .check("listings_24h", listings_last_24h)
+            .and_then(|r| r.count),
+        unique_stations: errors
+            .check("unique_stations", unique_stations)
+            .and_then(|r| r.count),
+        galaxy_cost: errors
+            .check("galaxy_cost", galaxy_cost)
+            .map(|r| r.galaxy_cost as u64),
+        most_expensive: errors.check("most_expensive", most_expensive).flatten(),
+        most_numerous: errors.check("most_numerous", most_numerous).flatten(),
+        unique_commodities: commodities.as_ref().map(|c| c.unique_commodities),
+        tracked_commodities: commodities.as_ref().map(|c| c.tracked_commodities),
+        top_stations: errors.check("top_stations", top_stations),
+        freshness: errors.check("freshness", freshness),
+        listings_per_day: errors.check("listings_per_day", listings_per_day),
+        pad_coverage: errors.check("pad_coverage", pad_coverage),
+        errors: errors.0,
+    }
+}
 
-    let mut total_galaxy_cost: u64 = 0;
-    if let Ok(group) = latest_listings {
-        for listing in group {
-            total_galaxy_cost += listing.buy_price as u64 * listing.stock as u64;
+impl Stats {
+    /// Flattens the statistics into `(statistic, value)` pairs of raw values. Values that aren't available are
+    /// left empty.
+    fn rows(&self) -> Vec<(String, String)> {
+        let mut rows = vec![
+            ("systems".to_string(), raw(&self.systems)),
+            ("stations".to_string(), raw(&self.stations)),
+            (
+                "stations_updated_24h".to_string(),
+                raw(&self.stations_updated_24h),
+            ),
+            ("listings".to_string(), raw(&self.listings)),
+            ("listings_24h".to_string(), raw(&self.listings_24h)),
+            ("unique_stations".to_string(), raw(&self.unique_stations)),
+            ("galaxy_cost".to_string(), raw(&self.galaxy_cost)),
+        ];
+        rows.extend(ItemStat::rows(&self.most_expensive, "most_expensive"));
+        rows.extend(ItemStat::rows(&self.most_numerous, "most_numerous"));
+        rows.push((
+            "unique_commodities".to_string(),
+            raw(&self.unique_commodities),
+        ));
+        rows.push((
+            "tracked_commodities".to_string(),
+            raw(&self.tracked_commodities),
+        ));
+        for (i, station) in self.top_stations.iter().flatten().enumerate() {
+            let prefix = format!("top_stations.{}", i + 1);
+            rows.push((format!("{prefix}.station"), station.station.clone()));
+            rows.push((format!("{prefix}.system"), station.system.clone()));
+            rows.push((format!("{prefix}.listings"), station.listings.to_string()));
+        }
+        let freshness = &self.freshness;
+        rows.push((
+            "freshness.last_hour".to_string(),
+            raw(&freshness.as_ref().map(|f| f.last_hour)),
+        ));
+        rows.push((
+            "freshness.last_day".to_string(),
+            raw(&freshness.as_ref().map(|f| f.last_day)),
+        ));
+        rows.push((
+            "freshness.last_week".to_string(),
+            raw(&freshness.as_ref().map(|f| f.last_week)),
+        ));
+        rows.push((
+            "freshness.older".to_string(),
+            raw(&freshness.as_ref().map(|f| f.older)),
+        ));
+        for day in self.listings_per_day.iter().flatten() {
+            rows.push((
+                format!("listings_per_day.{}", day.day),
+                day.listings.to_string(),
+            ));
+        }
+        for pad in self.pad_coverage.iter().flatten() {
+            let prefix = format!("pad_coverage.{}", pad.pad);
+            rows.push((format!("{prefix}.stations"), pad.stations.to_string()));
+            rows.push((
+                format!("{prefix}.stations_with_listings"),
+                pad.stations_with_listings.to_string(),
+            ));
+        }
+        for error in &self.errors {
+            rows.push((format!("error.{}", error.statistic), error.error.clone()));
         }
+        rows
+    }
+
+    /// Renders the statistics as a human readable table
+    fn table(&self) -> Table {
+        let mut table = Table::new();
+        table.load_preset(UTF8_FULL);
+        // table.apply_modifier(UTF8_ROUND_CORNERS);
+        table.set_header(vec!["Statistic", "Value"]);
+        table.add_row(vec!["Systems", &count(&self.systems)]);
+        table.add_row(vec!["Stations", &count(&self.stations)]);
+        table.add_row(vec![
+            "Stations updated (last 24h)",
+            &count(&self.stations_updated_24h),
+        ]);
+        table.add_row(vec!["Listings", &count(&self.listings)]);
+        table.add_row(vec!["Listings (last 24h)", &count(&self.listings_24h)]);
+        table.add_row(vec![
+            "Unique stations tracked",
+            &match (self.unique_stations, self.stations) {
+                (Some(unique), Some(total)) if total > 0 => format!(
+                    "{} ({}% of all stations)",
+                    unique.to_formatted_string(&Locale::en_AU),
+                    (unique as f32) / (total as f32) * 100.0
+                ),
+                (unique, _) => count(&unique),
+            },
+        ]);
+        table.add_row(vec![
+            "Cost to buy the entire galaxy",
+            &match self.galaxy_cost {
+                Some(cost) => format!(
+                    "${}\n({})",
+                    cost.to_formatted_string(&Locale::en_AU),
+                    (precision_f64(cost as f64, 3) as u64).to_english()
+                ),
+                None => NOT_AVAILABLE.to_string(),
+            },
+        ]);
+        table.add_row(vec![
+            "Most expensive item",
+            &match &self.most_expensive {
+                Some(exp) => format!(
+                    "{}\nFrom {} in {}\nCost ${}, units available {}\nListed at {}",
+                    exp.commodity,
+                    exp.station,
+                    exp.system,
+                    exp.buy_price.to_formatted_string(&Locale::en_AU),
+                    exp.stock.to_formatted_string(&Locale::en_AU),
+                    exp.listed_at
+                ),
+                None => NOT_AVAILABLE.to_string(),
+            },
+        ]);
+        table.add_row(vec![
+            "Most numerous item",
+            &match &self.most_numerous {
+                Some(num) => format!(
+                    "{}\nFrom {} in {}\nCost ${}, units available {}",
+                    num.commodity,
+                    num.station,
+                    num.system,
+                    num.buy_price.to_formatted_string(&Locale::en_AU),
+                    num.stock.to_formatted_string(&Locale::en_AU)
+                ),
+                None => NOT_AVAILABLE.to_string(),
+            },
+        ]);
+        table.add_row(vec!["Unique commodities", &count(&self.unique_commodities)]);
+        table.add_row(vec![
+            "Commodities tracked (station, commodity)",
+            &count(&self.tracked_commodities),
+        ]);
+        table.add_row(vec![
+            "Largest markets",
+            &lines(&self.top_stations, |s| {
+                format!(
+                    "{} in {} ({} commodities)",
+                    s.station,
+                    s.system,
+                    s.listings.to_formatted_string(&Locale::en_AU)
+                )
+            }),
+        ]);
+        table.add_row(vec![
+            "Markets by last update",
+            &match &self.freshness {
+                Some(freshness) => format!(
+                    "< 1 hour: {}\n1-24 hours: {}\n1-7 days: {}\nOlder: {}",
+                    freshness.last_hour.to_formatted_string(&Locale::en_AU),
+                    freshness.last_day.to_formatted_string(&Locale::en_AU),
+                    freshness.last_week.to_formatted_string(&Locale::en_AU),
+                    freshness.older.to_formatted_string(&Locale::en_AU)
+                ),
+                None => NOT_AVAILABLE.to_string(),
+            },
+        ]);
+        table.add_row(vec![
+            "Listings per day (last 30 days)",
+            &lines(&self.listings_per_day, |d| {
+                format!(
+                    "{}: {}",
+                    d.day,
+                    d.listings.to_formatted_string(&Locale::en_AU)
+                )
+            }),
+        ]);
+        table.add_row(vec![
+            "Coverage by landing pad",
+            &lines(&self.pad_coverage, |p| {
+                format!(
+                    "{}: {} of {} stations",
+                    p.pad,
+                    p.stations_with_listings.to_formatted_string(&Locale::en_AU),
+                    p.stations.to_formatted_string(&Locale::en_AU)
+                )
+            }),
+        ]);
+
+        table
+    }
+}
+
+pub async fn display_stats(url: String, pool_size: u32, format: StatsFormat) -> Result<()> {
+    info!("Setting up PostgreSQL pool on {}", url);
+    let var_name = PgPoolOptions::new();
+    let pool = var_name.max_connections(pool_size).connect(&url).await?;
+
+    info!("Computing statistics");
+    let stats = compute_stats(&pool).await;
+
+    match format {
+        StatsFormat::Table => println!("{}", stats.table()),
+        StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+        StatsFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            writer.write_record(["statistic", "value"])?;
+            for (statistic, value) in stats.rows() {
+                writer.write_record([statistic.as_str(), value.as_str()])?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    if !stats.errors.is_empty() {
+        warn!(
+            "{} statistics could not be computed, shown as {}",
+            stats.errors.len(),
+            NOT_AVAILABLE
+        );
     }
-    let exp = most_expensive.unwrap();
-    let num = most_numerous.unwrap();
-
-    let mut table = Table::new();
-    table.load_preset(UTF8_FULL);
-    // table.apply_modifier(UTF8_ROUND_CORNERS);
-    table.set_header(vec!["Statistic", "Value"]);
-    table.add_row(vec![
-        "Systems",
-        &num_systems
-            .unwrap()
-            .count
-            .unwrap()
-            .to_formatted_string(&Locale::en_AU),
-    ]);
-    table.add_row(vec![
-        "Stations",
-        &total_stations_count.to_formatted_string(&Locale::en_AU),
-    ]);
-    table.add_row(vec![
-        "Stations updated (last 24h)",
-        &stations_updated_24h
-            .unwrap()
-            .unique_stations
-            .unwrap()
-            .to_formatted_string(&Locale::en_AU),
-    ]);
-    table.add_row(vec![
-        "Listings",
-        &num_listings
-            .unwrap()
-            .count
-            .unwrap()
-            .to_formatted_string(&Locale::en_AU),
-    ]);
-    table.add_row(vec![
-        "Listings (last 24h)",
-        &listings_last_24h
-            .unwrap()
-            .count
-            .unwrap()
-            .to_formatted_string(&Locale::en_AU),
-    ]);
-    table.add_row(vec![
-        "Unique stations tracked",
-        format!(
-            "{} ({}% of all stations)",
-            unique_stations_count.to_formatted_string(&Locale::en_AU),
-            (unique_stations_count as f32) / (total_stations_count as f32) * 100.0
-        )
-        .as_str(),
-    ]);
-    table.add_row(vec![
-        "Cost to buy the entire galaxy",
-        &format!(
-            "${}\n({})",
-            total_galaxy_cost.to_formatted_string(&Locale::en_AU),
-            (precision_f64(total_galaxy_cost as f64, 3) as u64).to_english()
-        ),
-    ]);
-    table.add_row(vec![
-        "Most expensive item",
-        &format!(
-            "{}\nFrom {} in {}\nCost ${}, units available {}\nListed at {}",
-            &exp.commodity_name,
-            &exp.station_name,
-            &exp.sys_name,
-            &exp.buy_price.to_formatted_string(&Locale::en_AU),
-            &exp.stock.to_formatted_string(&Locale::en_AU),
-            &exp.listed_at
-        ),
-    ]);
-    table.add_row(vec![
-        "Most numerous item",
-        &format!(
-            "{}\nFrom {} in {}\nCost ${}, units available {}",
-            &num.commodity_name,
-            &num.station_name,
-            &num.sys_name,
-            &num.buy_price.to_formatted_string(&Locale::en_AU),
-            &num.stock.to_formatted_string(&Locale::en_AU)
-        ),
-    ]);
-
-    // top 5 stations with most listings (i.e. the most visited ones)
-    // number of unique commodities tracked
-    // total number of commodities tracked
-
-    println!("{table}");
     Ok(())
 }
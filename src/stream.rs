@@ -0,0 +1,150 @@
+// Fans out market updates received by the EDDN listener to connected clients as Server-Sent Events, so that
+// consumers like the web dashboard don't have to poll Postgres.
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use chrono::NaiveDateTime;
+use futures::Stream;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::Result;
+
+/// Number of updates buffered per client before slow clients start missing updates
+pub const STREAM_CAPACITY: usize = 1024;
+
+/// A market update that has been committed to the database
+#[derive(Serialize, Clone, Debug)]
+pub struct MarketUpdate {
+    pub market_id: i64,
+    pub system_name: String,
+    pub station_name: String,
+    /// Position of the station's system, if we know about it
+    pub coords: Option<[f64; 3]>,
+    pub listed_at: NaiveDateTime,
+    pub commodities: Vec<CommodityUpdate>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CommodityUpdate {
+    pub name: String,
+    pub mean_price: i32,
+    pub buy_price: i32,
+    pub sell_price: i32,
+    pub demand: i32,
+    pub stock: i32,
+}
+
+/// Subscription filter, supplied by clients as query parameters. All supplied filters must match.
+#[derive(Deserialize, Debug)]
+struct StreamFilter {
+    /// Only send updates from systems within `radius` ly of (`x`, `y`, `z`)
+    x: Option<f64>,
+    y: Option<f64>,
+    z: Option<f64>,
+    radius: Option<f64>,
+    /// Only send updates for this station
+    market_id: Option<i64>,
+    /// Comma separated list of commodity names; other commodities are stripped from updates
+    commodity: Option<String>,
+}
+
+impl StreamFilter {
+    /// Checks that the spatial filter is either complete or absent, so that a typo doesn't silently send the
+    /// client the whole stream
+    fn validate(&self) -> Result<(), String> {
+        let given = [
+            self.x.is_some(),
+            self.y.is_some(),
+            self.z.is_some(),
+            self.radius.is_some(),
+        ];
+        if given.iter().any(|given| *given) && !given.iter().all(|given| *given) {
+            return Err("x, y, z and radius must be given together".to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns the update as this client should see it, or None if it should not be sent at all
+    fn apply(&self, update: &MarketUpdate) -> Option<MarketUpdate> {
+        if self.market_id.is_some_and(|id| id != update.market_id) {
+            return None;
+        }
+
+        if let (Some(x), Some(y), Some(z), Some(radius)) = (self.x, self.y, self.z, self.radius) {
+            let [sx, sy, sz] = update.coords?;
+            let distance = ((sx - x).powi(2) + (sy - y).powi(2) + (sz - z).powi(2)).sqrt();
+            if distance > radius {
+                return None;
+            }
+        }
+
+        let mut update = update.clone();
+        if let Some(commodity) = &self.commodity {
+            let wanted: Vec<&str> = commodity.split(',').map(str::trim).collect();
+            update
+                .commodities
+                .retain(|c| wanted.iter().any(|w| w.eq_ignore_ascii_case(&c.name)));
+            if update.commodities.is_empty() {
+                return None;
+            }
+        }
+
+        Some(update)
+    }
+}
+
+async fn market_stream(
+    State(sender): State<broadcast::Sender<MarketUpdate>>,
+    Query(filter): Query<StreamFilter>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    filter
+        .validate()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    let stream = futures::stream::unfold(
+        (sender.subscribe(), filter),
+        |(mut receiver, filter)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(update) => {
+                        let Some(update) = filter.apply(&update) else {
+                            continue;
+                        };
+                        let event = Event::default()
+                            .event("market")
+                            .data(serde_json::to_string(&update).unwrap_or_default());
+                        return Some((Ok(event), (receiver, filter)));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Stream client lagged, skipped {} updates", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Serves the market update stream at `/markets/stream` until the process exits
+pub async fn serve_stream(bind: String, sender: broadcast::Sender<MarketUpdate>) -> Result<()> {
+    let app = Router::new()
+        .route("/markets/stream", get(market_stream))
+        .with_state(sender);
+
+    info!("Streaming market updates on http://{}/markets/stream", bind);
+    let listener = tokio::net::TcpListener::bind(&bind).await?;
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}